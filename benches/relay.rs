@@ -0,0 +1,108 @@
+//! Compares throughput and CPU time of splice (on Linux) and buffered relaying on loopback.
+//!
+//! Run with `cargo bench --bench relay`, optionally passing the MiB to transfer.
+#[macro_use] extern crate log;
+#[macro_use] extern crate mioco;
+#[macro_use] extern crate failure_derive;
+#[macro_use] extern crate failure;
+extern crate libc;
+
+use std::env;
+use std::io::{Read, Write};
+use std::mem;
+use std::net::SocketAddr;
+use std::time::Instant;
+use mioco::tcp::{TcpListener, TcpStream};
+
+#[path = "../src/utils.rs"]
+#[allow(dead_code)]
+mod utils;
+#[path = "../src/relay.rs"]
+#[allow(dead_code)]
+mod relay;
+
+use utils::Result;
+
+/// Seconds of user and system CPU time used by the process
+fn cpu_time() -> f64 {
+    let mut usage: libc::rusage = unsafe { mem::zeroed() };
+    unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+    let seconds = |x: libc::timeval| x.tv_sec as f64 + x.tv_usec as f64 / 1e6;
+    seconds(usage.ru_utime) + seconds(usage.ru_stime)
+}
+
+/// Sends `total` bytes through a relay running `relay_fn`, returning wall and CPU seconds
+fn run(total: usize, relay_fn: fn(&mut TcpStream, &mut TcpStream, &relay::Activity, relay::Direction) -> Result<()>) -> Result<(f64, f64)> {
+    let any: SocketAddr = "127.0.0.1:0".parse()?;
+    let relay_listener = TcpListener::bind(&any)?;
+    let sink_listener = TcpListener::bind(&any)?;
+    let relay_addr = relay_listener.local_addr()?;
+    let sink_addr = sink_listener.local_addr()?;
+
+    let start = Instant::now();
+    let cpu_start = cpu_time();
+    let relay = mioco::spawn(move || -> Result<()> {
+        let mut rx = relay_listener.accept()?;
+        let mut tx = TcpStream::connect(&sink_addr)?;
+        relay_fn(&mut rx, &mut tx, &relay::Activity::new(), relay::Direction::Up)?;
+        tx.shutdown(std::net::Shutdown::Write)?;
+        Ok(())
+    });
+    let sink = mioco::spawn(move || -> Result<usize> {
+        let mut stream = sink_listener.accept()?;
+        let mut buffer = vec![0u8; 65536];
+        let mut received = 0;
+        loop {
+            match stream.read(&mut buffer)? {
+                0 => return Ok(received),
+                x => received += x,
+            }
+        }
+    });
+    let mut source = TcpStream::connect(&relay_addr)?;
+    let chunk = vec![0x5au8; 65536];
+    let mut sent = 0;
+    while sent < total {
+        let len = chunk.len().min(total - sent);
+        source.write_all(&chunk[..len])?;
+        sent += len;
+    }
+    source.shutdown(std::net::Shutdown::Write)?;
+    relay.join().map_err(|x| format_err!("{:?}", x))??;
+    let received = sink.join().map_err(|x| format_err!("{:?}", x))??;
+    if received != total {
+        bail!("Received {} bytes, expected {}", received, total);
+    }
+    let elapsed = start.elapsed();
+    let wall = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    Ok((wall, cpu_time() - cpu_start))
+}
+
+fn bench() -> Result<()> {
+    let mib = env::args().skip(1).filter(|x| !x.starts_with('-')).next()
+        .map_or(Ok(1024), |x| x.parse())?;
+    let total = mib * 1024 * 1024;
+    let mut modes: Vec<(&str, fn(&mut TcpStream, &mut TcpStream, &relay::Activity, relay::Direction) -> Result<()>)> = vec![
+        ("buffered", relay::copy_forever),
+    ];
+    #[cfg(target_os = "linux")]
+    modes.push(("splice", relay::splice_forever));
+    for &(name, relay_fn) in &modes {
+        let (wall, cpu) = run(total, relay_fn)?;
+        println!(
+            "{:>8}: {} MiB in {:.3}s, {:.1} MiB/s, {:.3}s CPU",
+            name, mib, wall, mib as f64 / wall, cpu,
+        );
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut config = mioco::Config::new();
+    config.set_catch_panics(false);
+    config.set_thread_num(1);
+    if let Err(e) = mioco::Mioco::new_configured(config).start(bench).unwrap() {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,136 @@
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use failure::{Error, ResultExt};
+use serde_json::Value;
+use signal_hook::{self, SIGHUP};
+
+use socks5::SocksError;
+use http_connect::HttpProxyError;
+use happy_eyeballs::ConnectError;
+use timeout::TimeoutError;
+use utils::Result;
+
+/// Records waiting for the writer, beyond which new ones are dropped instead of stalling callers
+const QUEUE_SIZE: usize = 65536;
+
+struct Sink {
+    /// None for stdout
+    path: Option<PathBuf>,
+    writer: BufWriter<Box<Write + Send>>,
+}
+
+impl Sink {
+    fn reopen_if_requested(&mut self) {
+        let path = match self.path {
+            Some(ref x) if REOPEN.swap(false, Ordering::Relaxed) => x,
+            _ => return,
+        };
+        match open(path) {
+            Ok(x) => {
+                self.writer.flush().is_ok();
+                self.writer = BufWriter::new(Box::new(x));
+                info!("Reopened access log {}", path.display());
+            },
+            Err(e) => warn!("Failed to reopen access log {}: {}", path.display(), e),
+        };
+    }
+}
+
+lazy_static! {
+    static ref RECORDS: Mutex<Option<SyncSender<Value>>> = Mutex::new(None);
+    /// Set by SIGHUP, the file is reopened before the next record is written
+    static ref REOPEN: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+static DROPPED: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn open(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Writes queued records, flushing whenever the queue runs empty
+fn write_records(mut sink: Sink, records: Receiver<Value>) {
+    while let Ok(record) = records.recv() {
+        sink.reopen_if_requested();
+        let mut ret = writeln!(sink.writer, "{}", record);
+        while let Ok(record) = records.try_recv() {
+            ret = ret.and(writeln!(sink.writer, "{}", record));
+        }
+        if let Err(e) = ret.and(sink.writer.flush()) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+/// Writes access log records to `target`, which is a file path or `-` for stdout
+pub fn init(target: &str) -> Result<()> {
+    let sink = if target == "-" {
+        Sink { path: None, writer: BufWriter::new(Box::new(io::stdout())) }
+    } else {
+        let path = PathBuf::from(target);
+        let file = open(&path).context(format_err!("Failed to open access log {}", target))?;
+        signal_hook::flag::register(SIGHUP, REOPEN.clone())?;
+        Sink { path: Some(path), writer: BufWriter::new(Box::new(file)) }
+    };
+    let (tx, rx) = sync_channel(QUEUE_SIZE);
+    // Blocking IO on a thread, so slow disks or pipes don't stall coroutines
+    thread::spawn(move || write_records(sink, rx));
+    *RECORDS.lock().unwrap() = Some(tx);
+    Ok(())
+}
+
+pub fn is_enabled() -> bool {
+    RECORDS.lock().unwrap().is_some()
+}
+
+/// Queues `record` to be written as a line of JSON if the access log is enabled.
+/// Records are dropped while the writer is too far behind.
+pub fn log(record: Value) {
+    let records = RECORDS.lock().unwrap();
+    let tx = match *records {
+        Some(ref x) => x,
+        None => return,
+    };
+    if let Err(TrySendError::Full(_)) = tx.try_send(record) {
+        let count = DROPPED.fetch_add(1, Ordering::Relaxed) + 1;
+        if count.is_power_of_two() {
+            warn!("Access log can't keep up, dropped {} records in total", count);
+        }
+    }
+}
+
+/// Seconds since UNIX epoch with millisecond precision
+pub fn timestamp(time: SystemTime) -> f64 {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    since_epoch.as_secs() as f64 + (since_epoch.subsec_nanos() / 1_000_000) as f64 / 1000.0
+}
+
+/// Name of the enum variant of `x`, without its fields
+pub fn variant_name<T: Debug>(x: &T) -> String {
+    let name = format!("{:?}", x);
+    name.split(|c| c == '(' || c == ' ' || c == '{').next().unwrap().into()
+}
+
+/// Coarse kind of an error that is stable across messages, such as the variant of `SocksError`
+pub fn error_class(e: &Error) -> String {
+    if let Some(x) = e.downcast_ref::<SocksError>() {
+        variant_name(x)
+    } else if let Some(x) = e.downcast_ref::<HttpProxyError>() {
+        format!("Http{}", variant_name(x))
+    } else if e.downcast_ref::<TimeoutError>().is_some() {
+        "Timeout".into()
+    } else if e.downcast_ref::<ConnectError>().is_some() {
+        "ConnectFailed".into()
+    } else if let Some(x) = e.downcast_ref::<io::Error>() {
+        variant_name(&x.kind())
+    } else {
+        "Other".into()
+    }
+}
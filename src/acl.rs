@@ -0,0 +1,337 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::atomic::AtomicUsize;
+use std::time::Instant;
+use failure::Error;
+
+use utils::Result;
+
+/// Stops the limiter table from growing without bound under spoofed floods
+const MAX_TRACKED_CLIENTS: usize = 65536;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpPrefix {
+    addr: IpAddr,
+    len: u8,
+}
+
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(x) => {
+            let seg = x.segments();
+            if seg[..5].iter().all(|&x| x == 0) && seg[5] == 0xffff {
+                let octets = x.octets();
+                IpAddr::V4(Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]))
+            } else {
+                ip
+            }
+        },
+        _ => ip,
+    }
+}
+
+fn mask_bits(ip: IpAddr, len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(x) => {
+            let mask = if len == 0 { 0 } else { !0u32 << (32 - len) };
+            IpAddr::V4(Ipv4Addr::from(u32::from(x) & mask))
+        },
+        IpAddr::V6(x) => {
+            let mut octets = x.octets();
+            for (i, octet) in octets.iter_mut().enumerate() {
+                let bits = (len as usize).saturating_sub(i * 8).min(8);
+                *octet &= if bits == 0 { 0 } else { 0xffu8 << (8 - bits) };
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        },
+    }
+}
+
+impl IpPrefix {
+    pub fn new(addr: IpAddr, len: u8) -> Result<IpPrefix> {
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if len > max_len {
+            bail!("Prefix length {} is too long for {}", len, addr);
+        }
+        Ok(IpPrefix { addr: mask_bits(addr, len), len: len })
+    }
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = unmap(ip);
+        if ip.is_ipv4() != self.addr.is_ipv4() {
+            return false;
+        }
+        mask_bits(ip, self.len) == self.addr
+    }
+    pub fn overlaps(&self, other: &IpPrefix) -> bool {
+        self.contains(other.addr) || other.contains(self.addr)
+    }
+    /// Whether every address of `other` is in this prefix
+    pub fn covers(&self, other: &IpPrefix) -> bool {
+        self.len <= other.len && self.contains(other.addr)
+    }
+}
+
+impl FromStr for IpPrefix {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<IpPrefix> {
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap().parse().map_err(|_| format_err!("Invalid address in prefix: {}", s))?;
+        let len = match parts.next() {
+            Some(x) => x.parse().map_err(|_| format_err!("Invalid prefix length: {}", s))?,
+            None => if addr.is_ipv4() { 32 } else { 128 },
+        };
+        let unmapped = unmap(addr);
+        if unmapped != addr {
+            // Length of IPv4-mapped prefixes counts the ::ffff:0:0/96 part
+            if len < 96 {
+                bail!("Prefix length is too short for IPv4-mapped address: {}", s);
+            }
+            return IpPrefix::new(unmapped, len - 96);
+        }
+        IpPrefix::new(addr, len)
+    }
+}
+
+impl Display for IpPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+/// Source address filter. Deny entries take precedence, an empty allow list allows everyone.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    pub allow: Vec<IpPrefix>,
+    pub deny: Vec<IpPrefix>,
+}
+
+impl Acl {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|x| x.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|x| x.contains(ip))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimit {
+    /// Sustained queries per second per client network, 0 disables limiting
+    pub rate: u32,
+    pub burst: u32,
+    /// Every n-th limited query gets a truncated response instead of being dropped, 0 never slips
+    pub slip: u32,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Verdict {
+    Pass,
+    Slip,
+    Drop,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limited: u32,
+}
+
+/// Token bucket per client network, /24 for IPv4 and /56 for IPv6 as in BIND's RRL
+pub struct RateLimiter {
+    config: RateLimit,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimit) -> RateLimiter {
+        RateLimiter { config: config, buckets: HashMap::new() }
+    }
+    pub fn check(&mut self, ip: IpAddr) -> Verdict {
+        self.check_at(ip, Instant::now())
+    }
+    fn check_at(&mut self, ip: IpAddr, now: Instant) -> Verdict {
+        if self.config.rate == 0 {
+            return Verdict::Pass;
+        }
+        let ip = unmap(ip);
+        let key = mask_bits(ip, if ip.is_ipv4() { 24 } else { 56 });
+        let rate = self.config.rate as f64;
+        let burst = self.config.burst.max(1) as f64;
+        if self.buckets.len() >= MAX_TRACKED_CLIENTS && !self.buckets.contains_key(&key) {
+            // Buckets that are full again carry no state
+            self.buckets.retain(|_, x| {
+                let elapsed = now.duration_since(x.updated);
+                x.tokens + (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9) * rate < burst
+            });
+        }
+        let bucket = self.buckets.entry(key).or_insert(Bucket { tokens: burst, updated: now, limited: 0 });
+        let elapsed = now.duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9) * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.limited = 0;
+            return Verdict::Pass;
+        }
+        bucket.limited = bucket.limited.wrapping_add(1);
+        if self.config.slip != 0 && bucket.limited % self.config.slip == 0 {
+            Verdict::Slip
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+/// Queries that were not answered normally
+#[derive(Default)]
+pub struct DnsCounters {
+    pub denied: AtomicUsize,
+    pub limited: AtomicUsize,
+    pub slipped: AtomicUsize,
+}
+
+lazy_static! {
+    pub static ref DNS_COUNTERS: DnsCounters = DnsCounters::default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn prefix(s: &str) -> IpPrefix {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn ipv4_prefix() {
+        let x = prefix("192.168.1.77/24");
+        assert_eq!(x.to_string(), "192.168.1.0/24");
+        assert!(x.contains(ip("192.168.1.1")));
+        assert!(x.contains(ip("::ffff:192.168.1.1")));
+        assert!(!x.contains(ip("192.168.2.1")));
+        assert!(!x.contains(ip("::c0a8:101")));
+    }
+
+    #[test]
+    fn ipv6_prefix() {
+        let x = prefix("2001:db8:1::5/48");
+        assert_eq!(x.to_string(), "2001:db8:1::/48");
+        assert!(x.contains(ip("2001:db8:1:ffff::1")));
+        assert!(!x.contains(ip("2001:db8:2::1")));
+        assert!(!x.contains(ip("32.1.13.184")));
+    }
+
+    #[test]
+    fn ipv4_mapped_prefix() {
+        assert_eq!(prefix("::ffff:10.1.2.3/112"), prefix("10.1.0.0/16"));
+        assert_eq!(prefix("::ffff:10.1.2.3"), prefix("10.1.2.3/32"));
+        assert!(prefix("::ffff:10.0.0.0/104").contains(ip("10.1.2.3")));
+        assert!("::ffff:10.0.0.0/95".parse::<IpPrefix>().is_err());
+    }
+
+    #[test]
+    fn zero_length() {
+        assert!(prefix("10.0.0.0/0").contains(ip("192.168.1.1")));
+        assert!(!prefix("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(prefix("::/0").contains(ip("2001:db8::1")));
+        assert!(!prefix("::/0").contains(ip("::ffff:10.0.0.1")));
+    }
+
+    #[test]
+    fn full_length() {
+        assert_eq!(prefix("10.0.0.1"), prefix("10.0.0.1/32"));
+        assert!(prefix("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!prefix("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert_eq!(prefix("::1"), prefix("::1/128"));
+        assert!(prefix("::1/128").contains(ip("::1")));
+        assert!(!prefix("::1/128").contains(ip("::2")));
+    }
+
+    #[test]
+    fn invalid_prefix() {
+        for x in &["10.0.0.0/33", "::/129", "10.0.0.0/", "10.0.0.0/x", "10.0.0.0/-1", "10.0.0/8", "example.com/8", ""] {
+            assert!(x.parse::<IpPrefix>().is_err(), "{} should be invalid", x);
+        }
+    }
+
+    #[test]
+    fn overlapping_prefixes() {
+        assert!(prefix("10.0.0.0/8").overlaps(&prefix("10.1.0.0/16")));
+        assert!(prefix("10.1.0.0/16").overlaps(&prefix("10.0.0.0/8")));
+        assert!(!prefix("10.1.0.0/16").overlaps(&prefix("10.2.0.0/16")));
+        assert!(prefix("10.0.0.0/8").covers(&prefix("10.1.0.0/16")));
+        assert!(!prefix("10.1.0.0/16").covers(&prefix("10.0.0.0/8")));
+    }
+
+    #[test]
+    fn acl_deny_wins() {
+        let acl = Acl { allow: vec![prefix("10.0.0.0/8")], deny: vec![prefix("10.1.0.0/16")] };
+        assert!(acl.permits(ip("10.2.0.1")));
+        assert!(!acl.permits(ip("10.1.0.1")));
+        assert!(!acl.permits(ip("192.168.0.1")));
+        assert!(Acl::default().permits(ip("192.168.0.1")));
+    }
+
+    fn new_limiter(rate: u32, burst: u32, slip: u32) -> RateLimiter {
+        RateLimiter::new(RateLimit { rate: rate, burst: burst, slip: slip })
+    }
+
+    #[test]
+    fn disabled_rate_limit() {
+        let mut limiter = new_limiter(0, 1, 0);
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(limiter.check_at(ip("10.0.0.1"), now), Verdict::Pass);
+        }
+    }
+
+    #[test]
+    fn burst() {
+        let mut limiter = new_limiter(10, 3, 0);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip("10.0.0.1"), now), Verdict::Pass);
+        }
+        assert_eq!(limiter.check_at(ip("10.0.0.2"), now), Verdict::Drop);
+        assert_eq!(limiter.check_at(ip("10.0.1.1"), now), Verdict::Pass);
+    }
+
+    #[test]
+    fn refill() {
+        let mut limiter = new_limiter(10, 3, 0);
+        let now = Instant::now();
+        for _ in 0..3 {
+            limiter.check_at(ip("2001:db8::1"), now);
+        }
+        assert_eq!(limiter.check_at(ip("2001:db8::1"), now), Verdict::Drop);
+        let now = now + Duration::from_millis(150);
+        assert_eq!(limiter.check_at(ip("2001:db8::2"), now), Verdict::Pass);
+        assert_eq!(limiter.check_at(ip("2001:db8::2"), now), Verdict::Drop);
+        // Never more than the burst
+        let now = now + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(limiter.check_at(ip("2001:db8::1"), now), Verdict::Pass);
+        }
+        assert_eq!(limiter.check_at(ip("2001:db8::1"), now), Verdict::Drop);
+    }
+
+    #[test]
+    fn slip() {
+        let mut limiter = new_limiter(1, 1, 3);
+        let now = Instant::now();
+        let verdicts: Vec<_> = (0..7).map(|_| limiter.check_at(ip("10.0.0.1"), now)).collect();
+        assert_eq!(verdicts, vec![Verdict::Pass, Verdict::Drop, Verdict::Drop, Verdict::Slip, Verdict::Drop, Verdict::Drop, Verdict::Slip]);
+        let mut limiter = new_limiter(1, 1, 0);
+        limiter.check_at(ip("10.0.0.1"), now);
+        for _ in 0..10 {
+            assert_eq!(limiter.check_at(ip("10.0.0.1"), now), Verdict::Drop);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use failure::ResultExt;
+
+use connection::{active_connections, kill_connection};
+use upstream::{self, SharedUpstreams};
+use health;
+use utils::Result;
+
+/// Seconds a client can take to send its command or read the response
+const CLIENT_TIMEOUT_SECS: u64 = 5;
+const MAX_COMMAND_SIZE: u64 = 4096;
+
+const HELP: &str = "\
+connections          List relaying connections
+kill ID              Close a connection
+health               Show health of upstreams
+caches               Dump servers learned to need strict handshakes, the only cached state:
+                     DNS answers are encoded from query names, so there is no DNS cache
+default NAME...      Replace default servers with aliases or pools
+";
+
+fn execute(command: &str, upstreams: &SharedUpstreams) -> Result<String> {
+    let args: Vec<_> = command.split_whitespace().collect();
+    let mut out = String::new();
+    match args.first().cloned() {
+        Some("connections") => {
+            for (id, summary) in active_connections() {
+                out += &format!("{} {}\n", id, summary);
+            }
+        },
+        Some("kill") => {
+            if args.len() != 2 {
+                bail!("Usage: kill ID");
+            }
+            let id = args[1].parse().map_err(|_| format_err!("Invalid connection ID: {}", args[1]))?;
+            if !kill_connection(id) {
+                bail!("No such connection: {}", id);
+            }
+            out += &format!("Killed connection {}\n", id);
+        },
+        Some("health") => {
+            let current = upstreams.current();
+            if current.default_names().is_empty() {
+                out += "default: --default-server-host\n";
+            } else {
+                out += &format!("default: {}\n", current.default_names().join(" "));
+            }
+            for (name, up, successes, failures) in health::snapshot() {
+                out += &format!("{} {} successes={} failures={}\n", name, if up { "up" } else { "down" }, successes, failures);
+            }
+        },
+        Some("caches") => {
+            out += "dns: no cache, answers are encoded from query names\n";
+            out += "strict-handshake:\n";
+            for server in upstream::learned_strict_servers() {
+                out += &format!("  {}\n", server);
+            }
+        },
+        Some("default") => {
+            let names: Vec<String> = args[1..].iter().map(|&x| x.into()).collect();
+            let mut replaced = (*upstreams.current()).clone();
+            replaced.set_default_aliases(&names)?;
+            health::retain(&replaced.health_checked());
+            upstreams.replace(replaced);
+            info!("Default servers changed to {} by admin", names.join(", "));
+            out += &format!("Default servers: {}\n", names.join(" "));
+        },
+        Some("help") | None => out += HELP,
+        Some(x) => bail!("Unknown command: {}", x),
+    };
+    Ok(out)
+}
+
+fn serve_client(stream: UnixStream, upstreams: &SharedUpstreams) -> Result<()> {
+    // Clients are served one at a time, a stuck one must not block the others for long
+    stream.set_read_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(CLIENT_TIMEOUT_SECS)))?;
+    let mut command = String::new();
+    BufReader::new((&stream).take(MAX_COMMAND_SIZE)).read_line(&mut command)?;
+    let response = match execute(command.trim(), upstreams) {
+        Ok(x) => x,
+        Err(e) => format!("Error: {}\n", e),
+    };
+    (&stream).write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Listens for admin commands on a Unix socket at `path`, one command per connection
+pub fn serve_admin(path: &str, upstreams: Arc<SharedUpstreams>) -> Result<()> {
+    if Path::new(path).exists() {
+        fs::remove_file(path).context(format_err!("Failed to remove stale admin socket {}", path))?;
+    }
+    let listener = UnixListener::bind(path).context(format_err!("Failed to bind admin socket {}", path))?;
+    info!("Serving admin commands on {}", path);
+    // Blocking IO on a thread, so admin commands keep working even if coroutines are stuck
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(x) => if let Err(e) = serve_client(x, &upstreams) {
+                    warn!("Admin connection failed: {}", e);
+                },
+                Err(e) => warn!("Failed to accept admin connection: {}", e),
+            };
+        }
+    });
+    Ok(())
+}
+
+/// Sends `args` as a command to the admin socket at `path` and prints the response
+pub fn run_client(path: &str, args: &[String]) -> Result<()> {
+    let mut stream = UnixStream::connect(path).context(format_err!("Failed to connect to admin socket {}", path))?;
+    stream.write_all(format!("{}\n", args.join(" ")).as_bytes())?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    print!("{}", response);
+    if response.starts_with("Error: ") {
+        bail!("Command failed");
+    }
+    Ok(())
+}
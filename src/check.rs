@@ -0,0 +1,445 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+use toml;
+
+use Opt;
+use acl::IpPrefix;
+use config::{ConfigFile, Settings, parse_value};
+use huffman;
+use pool::Pool;
+use socks5::ServerCredentials;
+use upstream::{Alias, Protocol, Rule, ServerHeader, TargetMatcher, DIRECT, parse_server};
+
+/// Piece of a line of TOML, as much as locating keys needs
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    /// Contents of a quoted string, escapes are kept as written
+    Str(String),
+}
+
+/// Splits `line` into quoted strings and other characters, dropping whitespace and comments
+fn tokenize(line: &str) -> Vec<Token> {
+    let mut ret = Vec::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => break,
+            '"' | '\'' => {
+                let mut s = String::new();
+                while let Some(x) = chars.next() {
+                    if x == c {
+                        break;
+                    }
+                    s.push(x);
+                    if x == '\\' && c == '"' {
+                        s.extend(chars.next());
+                    }
+                }
+                ret.push(Token::Str(s));
+            },
+            x if x.is_whitespace() => {},
+            x => ret.push(Token::Char(x)),
+        }
+    }
+    ret
+}
+
+/// Components of a dotted key, quoted components may contain dots
+fn key_path(tokens: &[Token]) -> Vec<String> {
+    let mut ret = vec![String::new()];
+    for token in tokens {
+        match *token {
+            Token::Char('.') => ret.push(String::new()),
+            Token::Char(c) => ret.last_mut().unwrap().push(c),
+            Token::Str(ref s) => ret.last_mut().unwrap().push_str(s),
+        }
+    }
+    ret
+}
+
+/// Problems found in a config file, located by scanning its source for keys
+struct Diagnostics<'a> {
+    path: &'a str,
+    lines: Vec<Vec<Token>>,
+    problems: Vec<(Option<usize>, String)>,
+}
+
+impl<'a> Diagnostics<'a> {
+    fn new(path: &'a str, source: &str) -> Diagnostics<'a> {
+        Diagnostics {
+            path: path,
+            lines: source.lines().map(tokenize).collect(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// 0-based index of the line assigning `key`, a path from the top level
+    fn find(&self, key: &[&str]) -> Option<usize> {
+        // None inside arrays of tables, which the config doesn't have
+        let mut table = Some(Vec::new());
+        for (i, tokens) in self.lines.iter().enumerate() {
+            if tokens.first() == Some(&Token::Char('[')) {
+                table = if tokens.get(1) == Some(&Token::Char('[')) {
+                    None
+                } else {
+                    let end = tokens.iter().rposition(|x| *x == Token::Char(']')).unwrap_or(tokens.len());
+                    Some(key_path(&tokens[1..end.max(1)]))
+                };
+                continue;
+            }
+            let eq = match tokens.iter().position(|x| *x == Token::Char('=')) {
+                Some(x) => x,
+                None => continue,
+            };
+            if let Some(ref table) = table {
+                let path = key_path(&tokens[..eq]);
+                if table.len() + path.len() == key.len() && table.iter().chain(&path).zip(key).all(|(a, b)| &a[..] == *b) {
+                    return Some(i);
+                }
+            }
+        }
+        None
+    }
+
+    /// 1-based line of `key`
+    fn line(&self, key: &[&str]) -> Option<usize> {
+        self.find(key).map(|x| x + 1)
+    }
+
+    /// Line of `name` in table `key`, or of `key` itself if the table is inline
+    fn entry_line(&self, key: &[&str], name: &str) -> Option<usize> {
+        let mut entry = key.to_vec();
+        entry.push(name);
+        self.line(&entry).or_else(|| self.line(key))
+    }
+
+    /// Line of the `index`-th string in the array assigned to `key`, or of `key` if it can't be found
+    fn element_line(&self, key: &[&str], index: usize) -> Option<usize> {
+        let start = self.find(key)?;
+        let mut depth = 0;
+        let mut count = 0;
+        for (i, tokens) in self.lines[start..].iter().enumerate() {
+            let tokens = if i == 0 {
+                let eq = tokens.iter().position(|x| *x == Token::Char('=')).unwrap();
+                &tokens[eq + 1..]
+            } else {
+                &tokens[..]
+            };
+            for token in tokens {
+                match *token {
+                    Token::Char('[') => depth += 1,
+                    Token::Char(']') => depth -= 1,
+                    Token::Str(_) if depth == 1 => {
+                        if count == index {
+                            return Some(start + i + 1);
+                        }
+                        count += 1;
+                    },
+                    _ => {},
+                }
+            }
+            if depth <= 0 {
+                break;
+            }
+        }
+        Some(start + 1)
+    }
+
+    fn report<T: Display>(&mut self, line: Option<usize>, message: T) {
+        self.problems.push((line, message.to_string()));
+    }
+
+    fn parse<T: FromStr>(&mut self, line: Option<usize>, key: &str, value: &str) -> Option<T> where T::Err: Display {
+        match parse_value(key, value) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.report(line, e);
+                None
+            },
+        }
+    }
+
+    /// Reports prefixes overlapping an earlier one, which is most likely a mistake in a set of networks
+    fn check_overlaps(&mut self, key: &str, prefixes: &[(Option<usize>, IpPrefix)]) {
+        for (i, &(line, ref prefix)) in prefixes.iter().enumerate() {
+            if let Some(&(_, ref other)) = prefixes[..i].iter().find(|x| x.1.overlaps(prefix)) {
+                self.report(line, format!("Prefix {} in {} overlaps with {}", prefix, key, other));
+            }
+        }
+    }
+
+    /// Reports prefixes that never match because an earlier one covers them, more specific ones first are fine
+    fn check_shadowed(&mut self, key: &str, prefixes: &[(Option<usize>, IpPrefix)]) {
+        for (i, &(line, ref prefix)) in prefixes.iter().enumerate() {
+            if let Some(&(_, ref other)) = prefixes[..i].iter().find(|x| x.1.covers(prefix)) {
+                self.report(line, format!("Prefix {} in {} is shadowed by {}", prefix, key, other));
+            }
+        }
+    }
+
+    /// Diagnostics in order of lines, those without a line first
+    fn finish(mut self) -> Vec<String> {
+        self.problems.sort_by_key(|x| x.0);
+        let path = self.path;
+        self.problems.into_iter().map(|(line, message)| match line {
+            Some(x) => format!("{}:{}: {}", path, x, message),
+            None => format!("{}: {}", path, message),
+        }).collect()
+    }
+}
+
+fn check_file(diag: &mut Diagnostics, opt: &Opt, file: &ConfigFile) {
+    if let Some(ref x) = file.bind {
+        let line = diag.line(&["bind"]);
+        diag.parse::<SocketAddrV6>(line, "bind", x);
+    }
+    for (i, x) in file.bind_dns.iter().enumerate() {
+        let line = diag.element_line(&["bind-dns"], i);
+        diag.parse::<SocketAddr>(line, &format!("bind-dns[{}]", i), x);
+    }
+    if let Some(ref x) = file.metrics_bind {
+        let line = diag.line(&["metrics-bind"]);
+        diag.parse::<SocketAddr>(line, "metrics-bind", x);
+    }
+
+    let up = &file.upstreams;
+    if let Some(ref x) = up.default_server_protocol {
+        let line = diag.line(&["upstreams", "default-server-protocol"]);
+        diag.parse::<Protocol>(line, "upstreams.default-server-protocol", x);
+    }
+    // Aliases and pools from the command line can be referenced too
+    let mut aliases: HashSet<String> = opt.alias.iter().map(|x| x.name.clone()).collect();
+    aliases.insert(DIRECT.into());
+    for (name, spec) in &up.aliases {
+        let line = diag.entry_line(&["upstreams", "aliases"], name);
+        if let Some(alias) = diag.parse::<Alias>(line, &format!("upstreams.aliases.{}", name), &format!("{}={}", name, spec)) {
+            aliases.insert(alias.name);
+        }
+    }
+    let mut pools: HashSet<String> = opt.pool.iter().map(|x| x.name.clone()).collect();
+    for (name, spec) in &up.pools {
+        let line = diag.entry_line(&["upstreams", "pools"], name);
+        let pool = match diag.parse::<Pool>(line, &format!("upstreams.pools.{}", name), &format!("{}={}", name, spec)) {
+            Some(x) => x,
+            None => continue,
+        };
+        if aliases.contains(&pool.name) {
+            diag.report(line, format!("Pool {} has the same name as an alias", pool.name));
+        }
+        for member in &pool.members {
+            if !aliases.contains(&member.alias) {
+                diag.report(line, format!("Unknown server alias in pool {}: {}", pool.name, member.alias));
+            }
+        }
+        pools.insert(pool.name);
+    }
+    let known = |name: &str| aliases.contains(name) || pools.contains(name);
+    let mut route_prefixes = Vec::new();
+    for (i, x) in up.routes.iter().enumerate() {
+        let line = diag.element_line(&["upstreams", "routes"], i);
+        if let Some(rule) = diag.parse::<Rule>(line, &format!("upstreams.routes[{}]", i), x) {
+            if !known(&rule.alias) {
+                diag.report(line, format!("Unknown server alias in rule: {}", rule.alias));
+            }
+            if let TargetMatcher::Prefix(prefix) = rule.matcher {
+                route_prefixes.push((line, prefix));
+            }
+        }
+    }
+    for (i, x) in up.default_server.iter().enumerate() {
+        if !known(&x.to_lowercase()) {
+            let line = diag.element_line(&["upstreams", "default-server"], i);
+            diag.report(line, format!("Unknown server alias: {}", x));
+        }
+    }
+    for (server, value) in &up.credentials {
+        let line = diag.entry_line(&["upstreams", "credentials"], server);
+        diag.parse::<ServerCredentials>(line, &format!("upstreams.credentials.{}", server), &format!("{}={}", server, value));
+    }
+    for (server, headers) in &up.proxy_headers {
+        for (i, header) in headers.iter().enumerate() {
+            let line = diag.element_line(&["upstreams", "proxy-headers", &server[..]], i)
+                .or_else(|| diag.line(&["upstreams", "proxy-headers"]));
+            diag.parse::<ServerHeader>(line, &format!("upstreams.proxy-headers.{}[{}]", server, i), &format!("{}={}", server, header));
+        }
+    }
+    if let Some(ref x) = file.health_check.target {
+        if let Err(e) = parse_server(x) {
+            let line = diag.line(&["health-check", "target"]);
+            diag.report(line, format!("Invalid health check target: {}", e));
+        }
+    }
+
+    let mut allow = Vec::new();
+    for (i, x) in file.dns.allow.iter().enumerate() {
+        let line = diag.element_line(&["dns", "allow"], i);
+        if let Some(prefix) = diag.parse::<IpPrefix>(line, &format!("dns.allow[{}]", i), x) {
+            allow.push((line, prefix));
+        }
+    }
+    let mut deny = Vec::new();
+    for (i, x) in file.dns.deny.iter().enumerate() {
+        let line = diag.element_line(&["dns", "deny"], i);
+        if let Some(prefix) = diag.parse::<IpPrefix>(line, &format!("dns.deny[{}]", i), x) {
+            deny.push((line, prefix));
+        }
+    }
+    diag.check_overlaps("dns.allow", &allow);
+    diag.check_overlaps("dns.deny", &deny);
+    diag.check_shadowed("upstreams.routes", &route_prefixes);
+}
+
+/// Checks the config file at `path` together with command line options, without resolving anything.
+/// Returns diagnostics prefixed by path and line where known, empty if the configuration is valid.
+pub fn check_config(path: &str, opt: &Opt) -> Vec<String> {
+    let mut source = String::new();
+    if let Err(e) = File::open(path).and_then(|mut x| x.read_to_string(&mut source)) {
+        return vec![format!("{}: Failed to read config file: {}", path, e)];
+    }
+    check_source(path, &source, opt)
+}
+
+fn check_source(path: &str, source: &str, opt: &Opt) -> Vec<String> {
+    let mut diag = Diagnostics::new(path, source);
+    for problem in huffman::check_dictionary() {
+        diag.report(None, format!("Encoding dictionary: {}", problem));
+    }
+    let file = match toml::from_str::<ConfigFile>(source) {
+        Ok(x) => x,
+        Err(e) => {
+            let line = e.line_col().map(|(line, _)| line + 1);
+            diag.report(line, e);
+            return diag.finish();
+        },
+    };
+    check_file(&mut diag, opt, &file);
+    // Anything else that would fail at startup, only meaningful once the parts above are valid
+    if diag.problems.is_empty() {
+        if let Err(e) = Settings::merge(opt, file).and_then(|x| x.upstreams()) {
+            diag.report(None, e);
+        }
+    }
+    diag.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(lines: &[&str]) -> Vec<String> {
+        check_source("test.toml", &lines.join("\n"), &Opt::default())
+    }
+
+    fn assert_diagnostics(actual: Vec<String>, expected: &[&str]) {
+        assert_eq!(actual.len(), expected.len(), "{:#?}", actual);
+        // Messages of std parse errors differ between Rust versions, those are only checked up to the key
+        for (x, y) in actual.iter().zip(expected) {
+            assert!(x.starts_with(y), "{:?} doesn't start with {:?}", x, y);
+        }
+    }
+
+    #[test]
+    fn valid_config() {
+        assert_diagnostics(check(&[
+            "bind = \"[::1]:44555\"",
+            "[upstreams]",
+            "default-server = [\"edge\", \"direct\"]",
+            "routes = [\"10.1.0.0/16=direct\", \"10.0.0.0/8=a\"]",
+            "[upstreams.aliases]",
+            "a = \"socks5://10.0.0.2:1080\"",
+            "[upstreams.pools]",
+            "edge = \"round-robin:a,direct\"",
+        ]), &[]);
+    }
+
+    #[test]
+    fn bad_entries() {
+        assert_diagnostics(check(&[
+            "# Listeners, keys like bind = \"[::]:1\" in comments are ignored",
+            "bind = \"[::1]:44555\"",
+            "bind-dns = [\"[::]:53\", \"nonsense\"]",
+            "metrics-bind = \"127.0.0.1\" # no port = error",
+            "",
+            "[upstreams]",
+            "default-server = [\"edge\", \"missing\"]",
+            "routes = [",
+            "    \"10.2.0.0/16=direct\", # more specific first is fine",
+            "    \"10.0.0.0/8=a\",",
+            "    # \"corp.example=nowhere\" used to be here",
+            "    \"10.1.0.0/16=a\",",
+            "    \"corp.example=nowhere\",",
+            "]",
+            "",
+            "[upstreams.aliases]",
+            "a = \"socks5://10.0.0.2:1080\"",
+            "b = \"socks5://10.0.0.3\"",
+            "",
+            "[upstreams.pools]",
+            "edge = \"least-connections:a,c\"",
+            "",
+            "[dns] # resolver = settings",
+            "allow = [\"10.0.0.0/8\", \"10.1.0.0/16\"]",
+            "deny = [\"10.0.0.0/8\", \"0.0.0/8\"]",
+        ]), &[
+            "test.toml:3: Invalid value of bind-dns[1]: ",
+            "test.toml:4: Invalid value of metrics-bind: ",
+            "test.toml:7: Unknown server alias: missing",
+            "test.toml:12: Prefix 10.1.0.0/16 in upstreams.routes is shadowed by 10.0.0.0/8",
+            "test.toml:13: Unknown server alias in rule: nowhere",
+            "test.toml:18: Invalid value of upstreams.aliases.b: Port is missing in server address: 10.0.0.3",
+            "test.toml:21: Unknown server alias in pool edge: c",
+            "test.toml:24: Prefix 10.1.0.0/16 in dns.allow overlaps with 10.0.0.0/8",
+            "test.toml:25: Invalid value of dns.deny[1]: Invalid address in prefix: 0.0.0/8",
+        ]);
+    }
+
+    #[test]
+    fn syntax_error() {
+        assert_diagnostics(check(&[
+            "bind = \"[::1]:44555\"",
+            "[dns",
+        ]), &["test.toml:2: "]);
+    }
+
+    #[test]
+    fn pool_named_like_alias() {
+        assert_diagnostics(check(&[
+            "[upstreams.aliases]",
+            "a = \"socks5://10.0.0.2:1080\"",
+            "[upstreams.pools]",
+            "a = \"round-robin:a\"",
+        ]), &["test.toml:4: Pool a has the same name as an alias"]);
+    }
+
+    #[test]
+    fn locate_keys() {
+        let source = [
+            "[[dns]]",
+            "allow = [\"x\"]",
+            "[upstreams]",
+            "aliases.b = \"socks5://x\"",
+            "\"aliases\".\"c.d\" = \"socks5://y\"",
+            "[dns] # allow = [\"y\"]",
+            "allow = [",
+            "  \"a\", 'b', # \"c\"",
+            "  \"c\",",
+            "]",
+            "deny = []",
+        ].join("\n");
+        let diag = Diagnostics::new("test.toml", &source);
+        assert_eq!(diag.line(&["upstreams", "aliases", "b"]), Some(4));
+        assert_eq!(diag.entry_line(&["upstreams", "aliases"], "c.d"), Some(5));
+        assert_eq!(diag.entry_line(&["upstreams", "aliases"], "e"), None);
+        assert_eq!(diag.line(&["dns", "allow"]), Some(7));
+        assert_eq!(diag.element_line(&["dns", "allow"], 0), Some(8));
+        assert_eq!(diag.element_line(&["dns", "allow"], 1), Some(8));
+        assert_eq!(diag.element_line(&["dns", "allow"], 2), Some(9));
+        assert_eq!(diag.element_line(&["dns", "allow"], 3), Some(7));
+        assert_eq!(diag.element_line(&["dns", "deny"], 0), Some(11));
+    }
+}
@@ -0,0 +1,504 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::Read;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use mioco::timer::Timer;
+use mioco;
+use failure::ResultExt;
+use signal_hook::{self, SIGHUP};
+use toml;
+
+use Opt;
+use acl::{Acl, IpPrefix, RateLimit};
+use connection::AcceptLimits;
+use dns::{DnsConfig, DnsHandle, TtlPolicy};
+use health::{self, HealthCheck};
+use pool::Pool;
+use shutdown;
+use socks5::ServerCredentials;
+use timeout::RelayTimeouts;
+use upstream::{Alias, Protocol, Proxy, Rule, ServerHeader, SharedUpstreams, Timeouts, Upstream, Upstreams, parse_server, server_from_host};
+use utils::Result;
+
+/// Contents of the TOML configuration file, keys are named after command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    pub bind: Option<String>,
+    pub bind_dns: Vec<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    pub access_log: Option<String>,
+    pub metrics_bind: Option<String>,
+    pub admin_socket: Option<String>,
+    pub upstreams: UpstreamSection,
+    pub timeouts: TimeoutSection,
+    pub limits: LimitSection,
+    pub health_check: HealthCheckSection,
+    pub dns: DnsSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UpstreamSection {
+    pub default_server_host: Option<String>,
+    pub default_server_port: Option<u16>,
+    pub default_server_protocol: Option<String>,
+    pub default_server: Vec<String>,
+    /// Keyed by alias name, valued by server spec
+    pub aliases: BTreeMap<String, String>,
+    /// Keyed by pool name, valued by `strategy:alias,alias...`
+    pub pools: BTreeMap<String, String>,
+    /// In format of `domain=alias` or `prefix=alias`, first matching rule wins
+    pub routes: Vec<String>,
+    /// Keyed by `host:port` or alias, valued by `username[:password]`
+    pub credentials: BTreeMap<String, String>,
+    pub strict_handshake: Vec<String>,
+    /// Keyed by `host:port` or alias
+    pub proxy_headers: BTreeMap<String, Vec<String>>,
+}
+
+/// All in seconds
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TimeoutSection {
+    pub connect: Option<u64>,
+    pub handshake: Option<u64>,
+    pub first_byte: Option<u64>,
+    pub idle: Option<u64>,
+    pub half_closed: Option<u64>,
+    pub max_lifetime: Option<u64>,
+    pub drain: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LimitSection {
+    pub max_handshakes: Option<usize>,
+    pub accept_queue: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HealthCheckSection {
+    pub interval: Option<u64>,
+    pub target: Option<String>,
+    pub rise: Option<u32>,
+    pub fall: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DnsSection {
+    pub ttl_stateless: Option<u32>,
+    pub ttl_ipv4: Option<u32>,
+    pub ttl_resolved: Option<u32>,
+    pub ttl_alias: Option<u32>,
+    pub ttl_negative: Option<u32>,
+    pub synthesize_https: Option<bool>,
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    pub rate_limit: Option<u32>,
+    pub rate_burst: Option<u32>,
+    pub rate_slip: Option<u32>,
+}
+
+impl ConfigFile {
+    pub fn read(path: &str) -> Result<(ConfigFile, String)> {
+        let mut source = String::new();
+        File::open(path).and_then(|mut x| x.read_to_string(&mut source))
+            .context(format_err!("Failed to read config file {}", path))?;
+        let config = toml::from_str(&source).map_err(|e| format_err!("{}: {}", path, e))?;
+        Ok((config, source))
+    }
+}
+
+/// Parses `value` of config key `key`, naming the key in errors
+pub fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T> where T::Err: Display {
+    value.parse().map_err(|e| format_err!("Invalid value of {}: {}", key, e))
+}
+
+fn parse_list<T: FromStr>(key: &str, values: &[String]) -> Result<Vec<T>> where T::Err: Display {
+    values.iter().enumerate().map(|(i, x)| parse_value(&format!("{}[{}]", key, i), x)).collect()
+}
+
+/// Settings from command line and config file, command line taking precedence
+#[derive(Clone)]
+pub struct Settings {
+    pub bind: SocketAddrV6,
+    pub bind_dns: Vec<SocketAddr>,
+    pub user: String,
+    pub group: String,
+    pub access_log: Option<String>,
+    pub metrics_bind: Option<SocketAddr>,
+    pub admin_socket: Option<String>,
+    pub default_server: Upstream,
+    pub default_aliases: Vec<String>,
+    pub aliases: Vec<Alias>,
+    pub pools: Vec<Pool>,
+    pub routes: Vec<Rule>,
+    pub credentials: Vec<ServerCredentials>,
+    pub strict_handshake: Vec<String>,
+    pub proxy_headers: Vec<ServerHeader>,
+    pub timeouts: Timeouts,
+    pub drain_timeout_ms: u64,
+    pub limits: AcceptLimits,
+    pub health_check: Option<HealthCheck>,
+    pub ttl: TtlPolicy,
+    pub synthesize_https: bool,
+    pub acl: Acl,
+    pub rate_limit: RateLimit,
+}
+
+impl Settings {
+    /// Merges `opt` with the config file it names, if any
+    pub fn load(opt: &Opt) -> Result<Settings> {
+        let file = match opt.config {
+            Some(ref path) => ConfigFile::read(path)?.0,
+            None => ConfigFile::default(),
+        };
+        Settings::merge(opt, file)
+    }
+
+    pub fn merge(opt: &Opt, file: ConfigFile) -> Result<Settings> {
+        let up = file.upstreams;
+        let dns = file.dns;
+        let health = file.health_check;
+        let timeouts = file.timeouts;
+        let default_server_protocol = match opt.default_server_protocol {
+            Some(x) => x,
+            None => match up.default_server_protocol {
+                Some(ref x) => parse_value::<Protocol>("upstreams.default-server-protocol", x)?,
+                None => Protocol::Socks5,
+            },
+        };
+        let default_server = Upstream::new(Proxy::new(
+            default_server_protocol,
+            server_from_host(
+                opt.default_server_host.as_ref().or(up.default_server_host.as_ref()).map_or("socks.rg", |x| &x[..]),
+                opt.default_server_port.or(up.default_server_port).unwrap_or(1080),
+            ),
+        ));
+        // Repeatable options are added to those from the file, taking precedence where order matters
+        let mut aliases = up.aliases.iter()
+            .map(|(name, spec)| parse_value(&format!("upstreams.aliases.{}", name), &format!("{}={}", name, spec)))
+            .collect::<Result<Vec<Alias>>>()?;
+        aliases.extend(opt.alias.iter().cloned());
+        let mut pools = up.pools.iter()
+            .map(|(name, spec)| parse_value(&format!("upstreams.pools.{}", name), &format!("{}={}", name, spec)))
+            .collect::<Result<Vec<Pool>>>()?;
+        pools.extend(opt.pool.iter().cloned());
+        let mut routes = opt.route.clone();
+        routes.extend(parse_list::<Rule>("upstreams.routes", &up.routes)?);
+        let mut credentials = up.credentials.iter()
+            .map(|(server, value)| parse_value(&format!("upstreams.credentials.{}", server), &format!("{}={}", server, value)))
+            .collect::<Result<Vec<ServerCredentials>>>()?;
+        credentials.extend(opt.credentials.iter().cloned());
+        let mut proxy_headers = Vec::new();
+        for (server, headers) in &up.proxy_headers {
+            for (i, header) in headers.iter().enumerate() {
+                proxy_headers.push(parse_value(&format!("upstreams.proxy-headers.{}[{}]", server, i), &format!("{}={}", server, header))?);
+            }
+        }
+        proxy_headers.extend(opt.proxy_header.iter().cloned());
+        let health_check = match opt.health_check_target.as_ref().or(health.target.as_ref()) {
+            Some(target) => Some(HealthCheck {
+                interval_ms: opt.health_check_interval.or(health.interval).unwrap_or(30).max(1) * 1000,
+                probe: parse_server(target).map_err(|e| format_err!("Invalid health check target: {}", e))?,
+                rise: opt.health_check_rise.or(health.rise).unwrap_or(3).max(1),
+                fall: opt.health_check_fall.or(health.fall).unwrap_or(2).max(1),
+            }),
+            None => None,
+        };
+        let mut dns_allow = parse_list::<IpPrefix>("dns.allow", &dns.allow)?;
+        dns_allow.extend(opt.dns_allow.iter().cloned());
+        let mut dns_deny = parse_list::<IpPrefix>("dns.deny", &dns.deny)?;
+        dns_deny.extend(opt.dns_deny.iter().cloned());
+        Ok(Settings {
+            bind: match (opt.bind, file.bind) {
+                (Some(x), _) => x,
+                (None, Some(ref x)) => parse_value("bind", x)?,
+                (None, None) => "[::1]:44555".parse().unwrap(),
+            },
+            bind_dns: if !opt.bind_dns.is_empty() {
+                opt.bind_dns.clone()
+            } else if !file.bind_dns.is_empty() {
+                parse_list("bind-dns", &file.bind_dns)?
+            } else {
+                vec!["[::]:53".parse().unwrap()]
+            },
+            user: opt.user.clone().or(file.user).unwrap_or_else(|| "nobody".into()),
+            group: opt.group.clone().or(file.group).unwrap_or_else(|| "nogroup".into()),
+            access_log: opt.access_log.clone().or(file.access_log),
+            metrics_bind: match (opt.metrics_bind, file.metrics_bind) {
+                (Some(x), _) => Some(x),
+                (None, Some(ref x)) => Some(parse_value("metrics-bind", x)?),
+                (None, None) => None,
+            },
+            admin_socket: opt.admin_socket.clone().or(file.admin_socket),
+            default_server: default_server,
+            default_aliases: if opt.default_server.is_empty() { up.default_server } else { opt.default_server.clone() },
+            aliases: aliases,
+            pools: pools,
+            routes: routes,
+            credentials: credentials,
+            strict_handshake: up.strict_handshake.iter().chain(&opt.strict_handshake).map(|x| x.to_lowercase()).collect(),
+            proxy_headers: proxy_headers,
+            timeouts: Timeouts {
+                connect: opt.connect_timeout.or(timeouts.connect).unwrap_or(10) * 1000,
+                handshake: opt.handshake_timeout.or(timeouts.handshake).unwrap_or(10) * 1000,
+                first_byte: opt.first_byte_timeout.or(timeouts.first_byte).unwrap_or(0) * 1000,
+                relay: RelayTimeouts {
+                    idle: opt.idle_timeout.or(timeouts.idle).unwrap_or(0) * 1000,
+                    half_closed: opt.half_closed_timeout.or(timeouts.half_closed).unwrap_or(0) * 1000,
+                    lifetime: opt.max_lifetime.or(timeouts.max_lifetime).unwrap_or(0) * 1000,
+                },
+            },
+            drain_timeout_ms: opt.drain_timeout.or(timeouts.drain).unwrap_or(30) * 1000,
+            limits: AcceptLimits {
+                max_handshakes: opt.max_handshakes.or(file.limits.max_handshakes).unwrap_or(256),
+                max_queued: opt.accept_queue.or(file.limits.accept_queue).unwrap_or(1024),
+            },
+            health_check: health_check,
+            ttl: TtlPolicy {
+                stateless: opt.ttl_stateless.or(dns.ttl_stateless).unwrap_or(86400),
+                ipv4: opt.ttl_ipv4.or(dns.ttl_ipv4).unwrap_or(86400),
+                resolved: opt.ttl_resolved.or(dns.ttl_resolved).unwrap_or(15),
+                alias: opt.ttl_alias.or(dns.ttl_alias).unwrap_or(3600),
+                negative: opt.ttl_negative.or(dns.ttl_negative).unwrap_or(15),
+            },
+            synthesize_https: opt.synthesize_https || dns.synthesize_https.unwrap_or(false),
+            acl: Acl {
+                allow: dns_allow,
+                deny: dns_deny,
+            },
+            rate_limit: RateLimit {
+                rate: opt.dns_rate_limit.or(dns.rate_limit).unwrap_or(0),
+                burst: opt.dns_rate_burst.or(dns.rate_burst).unwrap_or(20),
+                slip: opt.dns_rate_slip.or(dns.rate_slip).unwrap_or(2),
+            },
+        })
+    }
+
+    /// Builds upstreams, checking that every referenced alias or pool exists
+    pub fn upstreams(&self) -> Result<Upstreams> {
+        let mut upstreams = Upstreams::new(
+            self.default_server.clone(),
+            self.aliases.clone(),
+            self.pools.clone(),
+            self.routes.clone(),
+            self.credentials.iter().map(|x| (x.server.clone(), x.credentials.clone())).collect(),
+            self.strict_handshake.iter().cloned().collect(),
+            self.proxy_headers.clone(),
+            self.timeouts.clone(),
+        )?;
+        if !self.default_aliases.is_empty() {
+            upstreams.set_default_aliases(&self.default_aliases)?;
+        }
+        Ok(upstreams)
+    }
+
+    pub fn dns_config(&self, upstreams: &Upstreams) -> DnsConfig {
+        DnsConfig {
+            ttl: self.ttl.clone(),
+            synthesize_https: self.synthesize_https,
+            aliases: upstreams.alias_names(),
+            acl: self.acl.clone(),
+            rate_limit: self.rate_limit.clone(),
+        }
+    }
+
+    /// Names of settings that differ from `other` but only take effect on restart
+    fn restart_required(&self, other: &Settings) -> Vec<&'static str> {
+        let mut ret = Vec::new();
+        if self.bind != other.bind { ret.push("bind"); }
+        if self.bind_dns != other.bind_dns { ret.push("bind-dns"); }
+        if self.user != other.user || self.group != other.group { ret.push("user and group"); }
+        if self.access_log != other.access_log { ret.push("access-log"); }
+        if self.metrics_bind != other.metrics_bind { ret.push("metrics-bind"); }
+        if self.admin_socket != other.admin_socket { ret.push("admin-socket"); }
+        if self.drain_timeout_ms != other.drain_timeout_ms { ret.push("timeouts.drain"); }
+        if self.limits != other.limits { ret.push("limits"); }
+        if self.health_check != other.health_check { ret.push("health-check"); }
+        ret
+    }
+}
+
+/// Health checks keep the parameters they were started with, but new default servers and pool members are checked too,
+/// and removed ones are no longer
+fn reload(opt: &Opt, current: &Settings, upstreams: &SharedUpstreams, dns: &DnsHandle, health_check: Option<&HealthCheck>) -> Result<Settings> {
+    let settings = Settings::load(opt)?;
+    let new_upstreams = settings.upstreams()?;
+    for name in settings.restart_required(current) {
+        warn!("Change of {} takes effect after restart", name);
+    }
+    if let Some(check) = health_check {
+        health::spawn_checkers(new_upstreams.health_checked(), check.clone(), settings.timeouts.clone());
+    }
+    let dns_config = settings.dns_config(&new_upstreams);
+    // Connections must know new aliases before DNS hands them out
+    upstreams.replace(new_upstreams);
+    dns.reload(dns_config);
+    Ok(settings)
+}
+
+/// Reloads the config file on SIGHUP, replacing upstreams and DNS settings for new queries and connections.
+/// The file is read after dropping privileges, so it must stay readable by the user given with `-u`.
+pub fn spawn_reloader(opt: Opt, settings: Settings, upstreams: Arc<SharedUpstreams>, dns: DnsHandle) -> Result<()> {
+    let requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, requested.clone())?;
+    mioco::spawn(move || {
+        let health_check = settings.health_check.clone();
+        let mut settings = settings;
+        loop {
+            let mut timer = Timer::new();
+            timer.set_timeout(shutdown::POLL_INTERVAL_MS as i64);
+            select!(r:timer => {});
+            if !requested.swap(false, Ordering::Relaxed) {
+                continue;
+            }
+            match reload(&opt, &settings, &upstreams, &dns, health_check.as_ref()) {
+                Ok(x) => {
+                    settings = x;
+                    info!("Reloaded configuration");
+                },
+                Err(e) => warn!("Failed to reload configuration, keeping the current one: {}", e),
+            };
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merge(opt: &Opt, source: &str) -> Result<Settings> {
+        Settings::merge(opt, toml::from_str(source).unwrap())
+    }
+
+    fn merge_error(opt: &Opt, source: &str) -> String {
+        merge(opt, source).err().expect("merge should fail").to_string()
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = merge(&Opt::default(), "").unwrap();
+        assert_eq!(settings.bind, "[::1]:44555".parse::<SocketAddrV6>().unwrap());
+        assert_eq!(settings.bind_dns, vec!["[::]:53".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(settings.user, "nobody");
+        assert_eq!(settings.group, "nogroup");
+        assert_eq!(settings.timeouts.connect, 10000);
+        assert_eq!(settings.drain_timeout_ms, 30000);
+        assert!(settings.health_check.is_none());
+        assert!(!settings.synthesize_https);
+    }
+
+    #[test]
+    fn command_line_overrides_scalars() {
+        let opt = Opt {
+            bind: Some("[::1]:1".parse().unwrap()),
+            user: Some("proxy".into()),
+            connect_timeout: Some(3),
+            ..Opt::default()
+        };
+        let settings = merge(&opt, r#"
+            bind = "[::1]:2"
+            user = "other"
+            group = "proxy"
+            [timeouts]
+            connect = 5
+            idle = 60
+        "#).unwrap();
+        assert_eq!(settings.bind, "[::1]:1".parse::<SocketAddrV6>().unwrap());
+        assert_eq!(settings.user, "proxy");
+        assert_eq!(settings.group, "proxy");
+        assert_eq!(settings.timeouts.connect, 3000);
+        assert_eq!(settings.timeouts.relay.idle, 60000);
+    }
+
+    #[test]
+    fn command_line_replaces_dns_listeners() {
+        let opt = Opt { bind_dns: vec!["127.0.0.1:53".parse().unwrap()], ..Opt::default() };
+        let settings = merge(&opt, r#"bind-dns = ["[::1]:53", "[::2]:53"]"#).unwrap();
+        assert_eq!(settings.bind_dns, vec!["127.0.0.1:53".parse::<SocketAddr>().unwrap()]);
+        let settings = merge(&Opt::default(), r#"bind-dns = ["[::1]:53", "[::2]:53"]"#).unwrap();
+        assert_eq!(settings.bind_dns.len(), 2);
+    }
+
+    #[test]
+    fn repeatables_are_concatenated() {
+        let opt = Opt {
+            alias: vec!["b=socks5://10.0.0.3:1080".parse().unwrap()],
+            strict_handshake: vec!["B".into()],
+            dns_allow: vec!["10.1.0.0/16".parse().unwrap()],
+            ..Opt::default()
+        };
+        let settings = merge(&opt, r#"
+            [upstreams]
+            strict-handshake = ["A"]
+            [upstreams.aliases]
+            a = "socks5://10.0.0.2:1080"
+            [dns]
+            allow = ["10.0.0.0/16"]
+        "#).unwrap();
+        let names: Vec<_> = settings.aliases.iter().map(|x| &x.name[..]).collect();
+        assert_eq!(names, vec!["a", "b"]);
+        assert_eq!(settings.strict_handshake, vec!["a", "b"]);
+        assert_eq!(settings.acl.allow.len(), 2);
+    }
+
+    #[test]
+    fn command_line_routes_come_first() {
+        let opt = Opt { route: vec!["example.com=direct".parse().unwrap()], ..Opt::default() };
+        let settings = merge(&opt, r#"
+            [upstreams]
+            routes = ["example.com=a"]
+            [upstreams.aliases]
+            a = "socks5://10.0.0.2:1080"
+        "#).unwrap();
+        let aliases: Vec<_> = settings.routes.iter().map(|x| &x.alias[..]).collect();
+        assert_eq!(aliases, vec!["direct", "a"]);
+    }
+
+    #[test]
+    fn default_servers_from_command_line_replace_file() {
+        let opt = Opt { default_server: vec!["direct".into()], ..Opt::default() };
+        let settings = merge(&opt, r#"
+            [upstreams]
+            default-server = ["a"]
+        "#).unwrap();
+        assert_eq!(settings.default_aliases, vec!["direct"]);
+    }
+
+    #[test]
+    fn synthesize_https_is_enabled_by_either() {
+        let enabled = Opt { synthesize_https: true, ..Opt::default() };
+        assert!(merge(&enabled, "[dns]\nsynthesize-https = false").unwrap().synthesize_https);
+        assert!(merge(&Opt::default(), "[dns]\nsynthesize-https = true").unwrap().synthesize_https);
+        assert!(!merge(&Opt::default(), "[dns]\nsynthesize-https = false").unwrap().synthesize_https);
+    }
+
+    #[test]
+    fn errors_name_the_key() {
+        assert!(merge_error(&Opt::default(), r#"bind = "nonsense""#).starts_with("Invalid value of bind: "));
+        assert!(merge_error(&Opt::default(), r#"bind-dns = ["[::]:53", "nonsense"]"#).starts_with("Invalid value of bind-dns[1]: "));
+        assert_eq!(
+            merge_error(&Opt::default(), "[upstreams]\nroutes = [\"example.com=direct\", \"=direct\"]"),
+            "Invalid value of upstreams.routes[1]: Empty domain in rule: =direct",
+        );
+        assert!(merge_error(&Opt::default(), "[upstreams.aliases]\nbad = \"socks5://host\"").starts_with("Invalid value of upstreams.aliases.bad: "));
+        assert!(merge_error(&Opt::default(), "[dns]\ndeny = [\"10.0.0.0/33\"]").starts_with("Invalid value of dns.deny[0]: "));
+        assert!(merge_error(&Opt::default(), "[health-check]\ntarget = \"example.com\"").starts_with("Invalid health check target: "));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<ConfigFile>("bnd = \"[::1]:1\"").is_err());
+        assert!(toml::from_str::<ConfigFile>("[timeouts]\nconect = 1").is_err());
+    }
+}
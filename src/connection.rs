@@ -1,16 +1,55 @@
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr};
-use std::io::Cursor;
-use mioco::tcp::TcpStream;
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, Ipv4Addr, Shutdown};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Display};
+use std::io::{Cursor, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use mioco::tcp::{TcpListener, TcpStream};
+use mioco::sync::mpsc::{channel, Receiver, Sender};
+use mioco::timer::Timer;
 use failure::{ResultExt};
 use bitstream_io::{BitReader, BE};
+use serde_json::Value;
 use mioco;
+use libc;
 
 use huffman::{DomainCode, READ_TREE};
-use socks5::{pipe_forever, socks5_connect, Socks5Target};
-use utils::Result;
+use socks5::Socks5Target;
+use relay::{pipe_forever, Activity, Direction};
+use upstream::{self, SharedUpstreams, Upstream, Upstreams};
+use pool::{ActiveConnection, Selection};
+use access_log::{self, error_class, timestamp};
+use metrics;
+use shutdown;
+use utils::{Result, ACCEPT_BACKOFF_MS, is_fd_exhausted, set_linger_zero};
+use timeout::{with_timeout, is_timeout, supervise_relay};
 
 lazy_static! {
     static ref USE_DEFAULT_SERVER: Socks5Target = Socks5Target::IP4(SocketAddrV4::new(Ipv4Addr::from(0), 0));
+    pub static ref CONNECTION_COUNTERS: ConnectionCounters = ConnectionCounters::default();
+}
+
+/// Connections between accept and the start of relaying
+#[derive(Default)]
+pub struct ConnectionCounters {
+    pub accepted: AtomicUsize,
+    /// Accepted connections waiting for a handshake slot
+    pub queued: AtomicUsize,
+    /// Connections being decoded and connected to upstreams
+    pub handshaking: AtomicUsize,
+    /// Connections relaying data
+    pub relaying: AtomicUsize,
+    /// Connections reset because the accept queue was full
+    pub overflowed: AtomicUsize,
+}
+
+/// Limits of connections that haven't started relaying yet
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptLimits {
+    pub max_handshakes: usize,
+    pub max_queued: usize,
 }
 
 struct DecodeAddr {
@@ -77,31 +116,399 @@ fn decode_addr(addr: SocketAddrV6) -> Result<DecodeAddr> {
     Ok(DecodeAddr {server: server, target: target})
 }
 
-pub fn handle_connection(stream: TcpStream, default_server: &Socks5Target) -> Result<()> {
-    let log_prefix = format!("[{}] -> [{}]", stream.peer_addr()?, stream.local_addr()?);
+/// Record of a connection after it's closed
+pub struct ConnectionSummary {
+    pub client: SocketAddr,
+    /// Server decoded from the address, `None` if the default one is used
+    pub server: Option<Socks5Target>,
+    /// Upstream actually connected through
+    pub upstream: String,
+    pub target: Socks5Target,
+    pub duration_ms: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// `client` or `server`, `None` if neither side closed before the connection ended
+    pub closed_first: Option<&'static str>,
+    /// Error that ended the connection, `None` if it was closed normally
+    pub error: Option<String>,
+    pub error_class: Option<String>,
+}
+
+impl ConnectionSummary {
+    fn new(client: SocketAddr, server: &Socks5Target, upstream: &Upstream, target: &Socks5Target, activity: &Activity, result: &Result<()>) -> ConnectionSummary {
+        ConnectionSummary {
+            client: client,
+            server: if server == &*USE_DEFAULT_SERVER { None } else { Some(server.clone()) },
+            upstream: upstream.to_string(),
+            target: target.clone(),
+            duration_ms: activity.elapsed_ms(),
+            bytes_up: activity.bytes(Direction::Up),
+            bytes_down: activity.bytes(Direction::Down),
+            closed_first: activity.first_finished().map(|x| x.sender()),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            error_class: result.as_ref().err().map(error_class),
+        }
+    }
+
+    /// Access log record
+    fn to_json(&self) -> Value {
+        let end = SystemTime::now();
+        let start = end - Duration::from_millis(self.duration_ms);
+        json!({
+            "type": "connection",
+            "start_time": timestamp(start),
+            "end_time": timestamp(end),
+            "client": self.client.to_string(),
+            "server": self.server.as_ref().map(|x| x.to_string()),
+            "upstream": self.upstream,
+            "target": self.target.to_string(),
+            "duration_ms": self.duration_ms,
+            "bytes_up": self.bytes_up,
+            "bytes_down": self.bytes_down,
+            "closed_first": self.closed_first,
+            "result": if self.error.is_some() { "error" } else { "closed" },
+            "error": self.error,
+            "error_class": self.error_class,
+        })
+    }
+
+    fn log(&self, log_prefix: &str) {
+        info!("{}: {}", log_prefix, self);
+        if access_log::is_enabled() {
+            access_log::log(self.to_json());
+        }
+    }
+}
+
+impl Display for ConnectionSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "client={} server=", self.client)?;
+        match self.server {
+            Some(ref x) => write!(f, "{}", x)?,
+            None => f.write_str("default")?,
+        };
+        write!(
+            f, " upstream={} target={} duration_ms={} bytes_up={} bytes_down={} closed_first={} reason=",
+            self.upstream, self.target, self.duration_ms, self.bytes_up, self.bytes_down,
+            self.closed_first.unwrap_or("none"),
+        )?;
+        match self.error {
+            Some(ref e) => write!(f, "{:?}", e),
+            None => f.write_str("closed"),
+        }
+    }
+}
+
+/// Relaying connection that can be listed and killed
+struct ActiveEntry {
+    client: SocketAddr,
+    server: Socks5Target,
+    upstream: Upstream,
+    target: Socks5Target,
+    activity: Arc<Activity>,
+    /// Clones of client and upstream streams
+    streams: [TcpStream; 2],
+}
+
+lazy_static! {
+    static ref ACTIVE_CONNECTIONS: Mutex<HashMap<usize, ActiveEntry>> = Mutex::new(HashMap::new());
+}
+
+static NEXT_CONNECTION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+fn register(entry: ActiveEntry) -> usize {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed) + 1;
+    ACTIVE_CONNECTIONS.lock().unwrap().insert(id, entry);
+    id
+}
+
+/// Returns false if the connection has been killed
+fn unregister(id: usize) -> bool {
+    ACTIVE_CONNECTIONS.lock().unwrap().remove(&id).is_some()
+}
+
+/// Relaying connections with their progress so far, sorted by ID
+pub fn active_connections() -> Vec<(usize, ConnectionSummary)> {
+    let mut ret: Vec<_> = ACTIVE_CONNECTIONS.lock().unwrap().iter()
+        .map(|(&id, x)| (id, ConnectionSummary::new(x.client, &x.server, &x.upstream, &x.target, &x.activity, &Ok(()))))
+        .collect();
+    ret.sort_by_key(|x| x.0);
+    ret
+}
+
+/// Shuts down both sides of a relaying connection, returns false if it doesn't exist
+pub fn kill_connection(id: usize) -> bool {
+    match ACTIVE_CONNECTIONS.lock().unwrap().remove(&id) {
+        Some(entry) => {
+            for stream in &entry.streams {
+                set_linger_zero(stream.as_raw_fd()).is_ok();
+                unsafe { libc::shutdown(stream.as_raw_fd(), libc::SHUT_RDWR) };
+            }
+            true
+        },
+        None => false,
+    }
+}
+
+/// Shuts down all relaying connections, returning their progress
+pub fn kill_all_connections() -> Vec<ConnectionSummary> {
+    let ids: Vec<_> = active_connections().into_iter().collect();
+    ids.into_iter()
+        .filter(|&(id, _)| kill_connection(id))
+        .map(|(_, summary)| summary)
+        .collect()
+}
+
+/// Resets the connection on close, and wakes up coroutines reading it
+fn reset_connection(stream: &TcpStream) {
+    set_linger_zero(stream.as_raw_fd()).is_ok();
+    stream.shutdown(Shutdown::Read).is_ok();
+}
+
+/// Forwards the first chunk from the client to the upstream, then notifies `written` to start the first byte timeout
+fn relay_first_up(rx: &mut TcpStream, tx: &mut TcpStream, timeout_ms: u64, activity: &Activity, written: Sender<()>) -> Result<()> {
+    if timeout_ms == 0 {
+        return Ok(());
+    }
+    let mut buffer = [0u8; 16384];
+    let ret: Result<()> = rx.read(&mut buffer).map_err(Into::into).and_then(|num_bytes| {
+        tx.write_all(&buffer[..num_bytes])?;
+        activity.transferred(Direction::Up, num_bytes);
+        Ok(())
+    });
+    // Also on failure, the other direction must not wait forever
+    written.send(()).is_ok();
+    ret
+}
+
+/// Forwards the first chunk from `rx` to `tx`, failing if it doesn't arrive in `timeout_ms`.
+/// The timeout starts once the client has sent something, so its think time isn't counted.
+fn relay_first_byte(rx: &mut TcpStream, tx: &mut TcpStream, timeout_ms: u64, activity: &Activity, up_written: Receiver<()>) -> Result<()> {
+    if timeout_ms == 0 {
+        return Ok(());
+    }
+    // Unless the upstream speaks first
+    select!(
+        r:up_written => {},
+        r:rx => {},
+    );
+    let mut buffer = [0u8; 16384];
+    let num_bytes = with_timeout(rx, timeout_ms, "First byte", |rx| Ok(rx.read(&mut buffer)?))?;
+    tx.write_all(&buffer[..num_bytes])?;
+    activity.transferred(Direction::Down, num_bytes);
+    Ok(())
+}
+
+/// Connects the client to its upstream and starts relaying in background.
+/// Failures to connect are logged in the connection summary, only other errors are returned.
+pub fn handle_connection(mut stream: TcpStream, upstreams: &Upstreams) -> Result<()> {
+    let client = stream.peer_addr()?;
+    let log_prefix = format!("[{}] -> [{}]", client, stream.local_addr()?);
     info!("{}", log_prefix);
     stream.set_nodelay(true)?;
     let remote = stream.local_addr()?; // With IP_TRANSPARENT our local address is encoded target address
-    let DecodeAddr { mut server, target } = decode_addr(if let SocketAddr::V6(addr) = remote {
+    let DecodeAddr { server, target } = decode_addr(if let SocketAddr::V6(addr) = remote {
         addr
     } else {
         bail!("Unexpected remote address: {}", remote)
     })?;
-    if server == *USE_DEFAULT_SERVER {
-        server = default_server.clone();
-    }
-    let transport = socks5_connect(server, target)?;
-    let stream_tx = stream.try_clone()?;
-    let transport_tx = transport.try_clone()?;
-    let handle = mioco::spawn(move || pipe_forever(stream_tx, transport_tx));
+    let activity = Arc::new(Activity::new());
+    let upstream = {
+        let selection = Selection { client: client.ip(), target: &target };
+        match server {
+            ref x if x == &*USE_DEFAULT_SERVER => upstreams.route(&selection),
+            // Port is never 0 for real servers
+            Socks5Target::Domain(ref name, 0) => upstreams.select(name, &selection)?,
+            ref x => upstreams.encoded(x.clone()),
+        }
+    };
+    let active = ActiveConnection::new(&upstream);
+    let handshake_start = Instant::now();
+    let upstream::Connection { stream: mut transport, early_data } = match upstream::connect(&upstream, target.clone(), upstreams.timeouts()) {
+        Ok(x) => x,
+        Err(e) => {
+            if is_timeout(&e) {
+                reset_connection(&stream);
+            }
+            metrics::connection_failed(&error_class(&e));
+            // Logged by the summary, not returned to be logged again
+            ConnectionSummary::new(client, &server, &upstream, &target, &activity, &Err(e)).log(&log_prefix);
+            return Ok(());
+        },
+    };
+    let handshake_time = handshake_start.elapsed();
+    metrics::handshake_completed(&upstream.to_string(), handshake_time.as_secs() as f64 + handshake_time.subsec_nanos() as f64 / 1e9);
+    let relaying = RelayingCount::new();
+    let first_byte_timeout = if early_data.is_empty() {
+        upstreams.timeouts().first_byte
+    } else {
+        stream.write_all(&early_data)?;
+        activity.transferred(Direction::Down, early_data.len());
+        0
+    };
+    let relay_timeouts = upstreams.timeouts().relay.clone();
+    let mut stream_tx = stream.try_clone()?;
+    let mut transport_tx = transport.try_clone()?;
+    let stream_watch = stream.try_clone()?;
+    let transport_watch = transport.try_clone()?;
+    let id = register(ActiveEntry {
+        client: client,
+        server: server.clone(),
+        upstream: upstream.clone(),
+        target: target.clone(),
+        activity: activity.clone(),
+        streams: [stream.try_clone()?, transport.try_clone()?],
+    });
+    let (done_tx, done) = channel::<()>();
+    let (up_written_tx, up_written) = channel::<()>();
+    let up_activity = activity.clone();
+    let up_done = done_tx.clone();
+    let up = mioco::spawn(move || {
+        let ret = relay_first_up(&mut stream_tx, &mut transport_tx, first_byte_timeout, &up_activity, up_written_tx)
+            .and_then(|_| pipe_forever(stream_tx, transport_tx, &up_activity, Direction::Up));
+        up_done.send(()).is_ok();
+        ret
+    });
+    let down_activity = activity.clone();
+    let down = mioco::spawn(move || {
+        let ret = match relay_first_byte(&mut transport, &mut stream, first_byte_timeout, &down_activity, up_written) {
+            Ok(_) => pipe_forever(transport, stream, &down_activity, Direction::Down),
+            Err(e) => {
+                if is_timeout(&e) {
+                    reset_connection(&stream);
+                }
+                Err(e)
+            },
+        };
+        done_tx.send(()).is_ok();
+        ret
+    });
     mioco::spawn(move || {
-        let result = pipe_forever(transport, stream)
-            .and_then(|_| handle.join().map_err(|x| format_err!("{:?}", x)));
-        if let Err(e) = result {
-            info!("{}: {}", log_prefix, e);
+        let supervised = if relay_timeouts.is_enabled() {
+            supervise_relay(&[&stream_watch, &transport_watch], &activity, &relay_timeouts, done)
         } else {
-            info!("{}: Connection closed", log_prefix);
+            Ok(())
+        };
+        if supervised.is_err() {
+            reset_connection(&stream_watch);
+        }
+        let down = down.join().map_err(|x| format_err!("{:?}", x)).and_then(|x| x);
+        let up = up.join().map_err(|x| format_err!("{:?}", x)).and_then(|x| x);
+        let mut result = supervised.and(down).and(up);
+        if !unregister(id) {
+            result = Err(format_err!("Connection killed"));
+        }
+        drop(active);
+        drop(relaying);
+        let summary = ConnectionSummary::new(client, &server, &upstream, &target, &activity, &result);
+        metrics::bytes_transferred(&summary.upstream, summary.bytes_up, summary.bytes_down);
+        summary.log(&log_prefix);
+    });
+    Ok(())
+}
+
+/// Counts a relaying connection until dropped, also when setting up the relay fails
+struct RelayingCount;
+
+impl RelayingCount {
+    fn new() -> RelayingCount {
+        CONNECTION_COUNTERS.relaying.fetch_add(1, Ordering::Relaxed);
+        RelayingCount
+    }
+}
+
+impl Drop for RelayingCount {
+    fn drop(&mut self) {
+        CONNECTION_COUNTERS.relaying.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns a handshake slot to the accept loop when dropped
+struct HandshakeSlot(Sender<()>);
+
+impl Drop for HandshakeSlot {
+    fn drop(&mut self) {
+        CONNECTION_COUNTERS.handshaking.fetch_sub(1, Ordering::Relaxed);
+        self.0.send(()).is_ok();
+    }
+}
+
+fn spawn_handler(stream: TcpStream, upstreams: &SharedUpstreams, slots: &Sender<()>) {
+    CONNECTION_COUNTERS.handshaking.fetch_add(1, Ordering::Relaxed);
+    let slot = HandshakeSlot(slots.clone());
+    let upstreams = upstreams.current();
+    mioco::spawn(move || {
+        if let Err(e) = handle_connection(stream, &upstreams) {
+            // Decoding the target or selecting the upstream failed, or the relay couldn't start
+            metrics::connection_failed(&error_class(&e));
+            warn!("{}", e);
         }
+        drop(slot);
     });
+}
+
+/// Accepts connections and handles each one in its own coroutine, with at most `limits.max_handshakes`
+/// connecting to upstreams at the same time. Excess connections wait in a queue of `limits.max_queued`.
+/// Returns once shutdown is requested, leaving accepted connections running.
+pub fn serve_connections(listener: TcpListener, upstreams: Arc<SharedUpstreams>, limits: AcceptLimits) -> Result<()> {
+    let (slots, returned_slots) = channel::<()>();
+    let mut available = limits.max_handshakes.max(1);
+    let mut queue = VecDeque::new();
+    while !shutdown::requested() {
+        let mut poll_timer = Timer::new();
+        poll_timer.set_timeout(shutdown::POLL_INTERVAL_MS as i64);
+        let mut backoff = false;
+        select!(
+            r:listener => {
+                match listener.try_accept() {
+                    Ok(Some(stream)) => {
+                        CONNECTION_COUNTERS.accepted.fetch_add(1, Ordering::Relaxed);
+                        if queue.len() >= limits.max_queued && available == 0 {
+                            let count = CONNECTION_COUNTERS.overflowed.fetch_add(1, Ordering::Relaxed) + 1;
+                            debug!("Accept queue is full, resetting connection from {:?} ({} in total)", stream.peer_addr(), count);
+                            reset_connection(&stream);
+                        } else {
+                            queue.push_back(stream);
+                        }
+                    },
+                    Ok(None) => {},
+                    // Errors like ECONNABORTED only affect one connection
+                    Err(e) => {
+                        warn!("Failed to accept connection: {}", e);
+                        backoff = is_fd_exhausted(&e);
+                    },
+                };
+            },
+            r:returned_slots => {
+                while returned_slots.try_recv().is_ok() {
+                    available += 1;
+                }
+            },
+            r:poll_timer => {},
+        );
+        if backoff {
+            // Accepting again right away would fail the same way until connections close
+            let mut timer = Timer::new();
+            timer.set_timeout(ACCEPT_BACKOFF_MS as i64);
+            select!(r:timer => {});
+        }
+        while available > 0 {
+            match queue.pop_front() {
+                Some(stream) => {
+                    available -= 1;
+                    spawn_handler(stream, &upstreams, &slots);
+                },
+                None => break,
+            }
+        }
+        CONNECTION_COUNTERS.queued.store(queue.len(), Ordering::Relaxed);
+    }
+    if !queue.is_empty() {
+        info!("Resetting {} queued connections on shutdown", queue.len());
+    }
+    for stream in queue {
+        reset_connection(&stream);
+    }
+    CONNECTION_COUNTERS.queued.store(0, Ordering::Relaxed);
     Ok(())
 }
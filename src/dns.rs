@@ -1,68 +1,168 @@
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs, Ipv6Addr};
-use std::io::{Write, Seek, Cursor, SeekFrom};
+use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs, Ipv4Addr, Ipv6Addr};
+use std::io::{Read, Write, Seek, Cursor, SeekFrom, ErrorKind};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
 use mioco::udp::UdpSocket;
+use mioco::tcp::{TcpListener, TcpStream};
+use mioco::timer::Timer;
 use failure::{ResultExt};
 use bitstream_io::{BitWriter, BE};
-use trust_dns_proto::op::{Message, MessageType, ResponseCode, Edns, OpCode};
-use trust_dns_proto::rr::{RecordType, Record, RData};
-use byteorder::{WriteBytesExt, NetworkEndian};
+use trust_dns_proto::op::{Message, MessageType, ResponseCode, OpCode};
+use trust_dns_proto::rr::{RecordType, Record, RData, Name};
+use trust_dns_proto::rr::rdata::{SOA, NULL};
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+use serde_json::Value;
 use mioco;
 
 use huffman::{DomainCode, COMPOSITE_CODES, WRITE_TREE};
 use socks5::Socks5Target;
-use utils::Result;
+use utils::{Result, ACCEPT_BACKOFF_MS, is_fd_exhausted};
+use timeout::with_timeout;
+use edns;
+use acl::{Acl, RateLimit, RateLimiter, Verdict, DNS_COUNTERS};
+use access_log::{self, timestamp, variant_name};
+use metrics;
+use shutdown;
 
-fn resolve_name(name: &str) -> Result<Ipv6Addr> {
+/// TTLs of synthesized records, by kind of the encoded answer
+#[derive(Debug, Clone)]
+pub struct TtlPolicy {
+    /// Domains encoded directly, the answer never changes
+    pub stateless: u32,
+    /// Raw IPv4 addresses
+    pub ipv4: u32,
+    /// Domains pre-resolved with `r---e.`
+    pub resolved: u32,
+    /// Server aliases referenced with `a---s.`
+    pub alias: u32,
+    /// Failed lookups, advertised in SOA
+    pub negative: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DnsConfig {
+    pub ttl: TtlPolicy,
+    /// Answer HTTPS queries with a record carrying `ipv6hint`, instead of NODATA
+    pub synthesize_https: bool,
+    /// Names of server aliases
+    pub aliases: HashSet<String>,
+    pub acl: Acl,
+    pub rate_limit: RateLimit,
+}
+
+const TYPE_HINFO: u16 = 13;
+const TYPE_HTTPS: u16 = 65;
+const SVC_PARAM_IPV6HINT: u16 = 6;
+/// Milliseconds a TCP client can take to send a query or receive a response, including idle time between queries
+const TCP_IDLE_TIMEOUT_MS: u64 = 10000;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum AnswerKind {
+    Stateless,
+    IPv4,
+    Resolved,
+    Alias,
+}
+
+impl TtlPolicy {
+    fn ttl(&self, kind: AnswerKind) -> u32 {
+        match kind {
+            AnswerKind::Stateless => self.stateless,
+            AnswerKind::IPv4 => self.ipv4,
+            AnswerKind::Resolved => self.resolved,
+            AnswerKind::Alias => self.alias,
+        }
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum EncodeError {
+    #[fail(display = "Invalid name: {}", _0)]
+    InvalidName(String),
+    #[fail(display = "Unable to resolve {} to IPv4 address", _0)]
+    ResolveFailed(String),
+    #[fail(display = "Unknown server alias: {}", _0)]
+    UnknownAlias(String),
+    #[fail(display = "Invalid port for proxy server: {}", _0)]
+    InvalidPort(String),
+    #[fail(display = "Unencodable character: {}", _0)]
+    UnencodableCharacter(char),
+    #[fail(display = "Not enough space to encode {}", _0)]
+    NoSpace(&'static str),
+}
+
+/// Encodes `name` into an address. Without `resolve`, domains to be pre-resolved are only validated and
+/// encoded as a placeholder, for answers that don't carry the address.
+fn resolve_name(name: &str, config: &DnsConfig, resolve: bool) -> Result<(Ipv6Addr, u32)> {
+    let ttl_policy = &config.ttl;
     let parts: Vec<_> = name.split(r".s---t.").collect();
-    if parts.len() != 2 && parts.len() != 4 {
-        bail!("Invalid name: {}", name);
+    let is_alias = parts.len() == 3 && parts[1].starts_with(r"a---s.");
+    if parts.len() != 2 && parts.len() != 4 && !is_alias {
+        return Err(EncodeError::InvalidName(name.into()))?;
     }
-    fn parse_part(part: &str, port: u16) -> Result<Socks5Target> {
+    fn parse_part(part: &str, port: u16, resolve: bool) -> Result<(Socks5Target, AnswerKind)> {
         match part.parse() {
-            Ok(x) => Ok(Socks5Target::IP4(SocketAddrV4::new(x, port))),
+            Ok(x) => Ok((Socks5Target::IP4(SocketAddrV4::new(x, port)), AnswerKind::IPv4)),
             Err(_) => {
                 if part.starts_with(r"r---e.") {
                     let req_domain = &part[6..];
+                    if !resolve {
+                        // Any specified address takes the same space as the resolved one would
+                        return Ok((Socks5Target::IP4(SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), port)), AnswerKind::Resolved));
+                    }
                     return match mioco::offload(|| (req_domain, port).to_socket_addrs()) {
                         Ok(x) => match x.filter(|x| x.is_ipv4()).next() {
-                            Some(addr) => Ok(addr.into()),
-                            None => bail!("Unable to resolve {} to IPv4 address", req_domain),
+                            Some(addr) => Ok((addr.into(), AnswerKind::Resolved)),
+                            None => Err(EncodeError::ResolveFailed(req_domain.into()).into()),
                         },
-                        Err(e) => bail!("Unable to resolve {} to IPv4 address: {}", req_domain, e),
+                        Err(e) => Err(EncodeError::ResolveFailed(format!("{} ({})", req_domain, e)).into()),
                     };
                 }
-                Ok(Socks5Target::Domain(part.into(), port))
+                Ok((Socks5Target::Domain(part.into(), port), AnswerKind::Stateless))
             },
         }
     }
-    let target = parse_part(parts[0], 0)?;
+    let (target, target_kind) = parse_part(parts[0], 0, resolve)?;
+    let mut ttl = ttl_policy.ttl(target_kind);
     let server = if parts.len() == 2 {
         Socks5Target::IP4(SocketAddrV4::new(0u32.into(), 0))
+    } else if is_alias {
+        let alias = parts[1][6..].to_lowercase();
+        if !config.aliases.contains(&alias) {
+            return Err(EncodeError::UnknownAlias(alias))?;
+        }
+        ttl = ttl.min(ttl_policy.ttl(AnswerKind::Alias));
+        // Encoded as domain with port 0, which is never a valid server port
+        Socks5Target::Domain(alias, 0)
     } else {
         let port = match parts[2].parse() {
             Ok(x) => x,
-            Err(_) => bail!("Invalid port for proxy server: {}", parts[2]),
+            Err(_) => return Err(EncodeError::InvalidPort(parts[2].into()))?,
         };
-        parse_part(parts[1], port)?
+        let (server, server_kind) = parse_part(parts[1], port, resolve)?;
+        ttl = ttl.min(ttl_policy.ttl(server_kind));
+        server
     };
     let mut octets = [0u8; 16];
-    fn write_domain<'a>(writer: &mut BitWriter<'a, BE>, domain: &String) -> Result<()> {
-        writer.write_bit(true)?;
+    fn write_domain<'a>(writer: &mut BitWriter<'a, BE>, domain: &String, what: &'static str) -> Result<()> {
+        writer.write_bit(true).map_err(|_| EncodeError::NoSpace(what))?;
         let domain = domain.to_lowercase();
         let mut remaining = &domain[..];
         while !remaining.is_empty() {
             match COMPOSITE_CODES.iter().find(|&x| remaining.starts_with(x)) {
                 Some(x) => {
-                    writer.write_huffman(&WRITE_TREE, DomainCode::Composite(x))?;
+                    writer.write_huffman(&WRITE_TREE, DomainCode::Composite(x)).map_err(|_| EncodeError::NoSpace(what))?;
                     remaining = &remaining[x.len()..];
                 },
                 None => {
                     let ch = remaining.chars().next().unwrap();
                     if ch != '\\' {
                         if !WRITE_TREE.has_symbol(DomainCode::Char(ch)) {
-                            bail!("Unencodable character: {}", ch);
+                            return Err(EncodeError::UnencodableCharacter(ch))?;
                         }
-                        writer.write_huffman(&WRITE_TREE, DomainCode::Char(ch))?;
+                        writer.write_huffman(&WRITE_TREE, DomainCode::Char(ch)).map_err(|_| EncodeError::NoSpace(what))?;
                     }
                     remaining = &remaining[1..];
                 },
@@ -83,7 +183,7 @@ fn resolve_name(name: &str) -> Result<Ipv6Addr> {
                     writer.write_bytes(&addr.ip().octets()).unwrap();
                 },
                 Socks5Target::Domain(domain, _) => {
-                    write_domain(&mut writer, domain)?;
+                    write_domain(&mut writer, domain, "target domain")?;
                 }
                 _ => bail!("Not implemented"),
             };
@@ -92,8 +192,8 @@ fn resolve_name(name: &str) -> Result<Ipv6Addr> {
                     writer.write_bit(false).is_ok();
                 },
                 Socks5Target::Domain(domain, port) => {
-                    write_domain(&mut writer, domain).context("Not enough space to encode domain of proxy server")?;
-                    writer.write(16, *port).context("Not enough space to encode port of proxy server")?;
+                    write_domain(&mut writer, domain, "domain of proxy server")?;
+                    writer.write(16, *port).map_err(|_| EncodeError::NoSpace("port of proxy server"))?;
                 }
                 _ => bail!("Not implemented"),
             };
@@ -102,7 +202,7 @@ fn resolve_name(name: &str) -> Result<Ipv6Addr> {
         if let Socks5Target::IP4(addr) = &server {
             if !addr.ip().is_unspecified() {
                 if cursor.position() > 10 {
-                    bail!("Not enough space to encode server IP and port");
+                    return Err(EncodeError::NoSpace("server IP and port"))?;
                 }
                 cursor.seek(SeekFrom::Start(10)).unwrap();
                 cursor.write_all(&addr.ip().octets()).unwrap();
@@ -111,63 +211,463 @@ fn resolve_name(name: &str) -> Result<Ipv6Addr> {
         }
     }
     let ip = octets.into();
-    debug!("{}: [{}] -> [{}] => {}", name, server, target, ip);
-    Ok(ip)
+    debug!("{}: [{}] -> [{}] => {} (TTL {})", name, server, target, ip, ttl);
+    Ok((ip, ttl))
 }
 
-fn resolve_dns_request(msg: &mut Message) -> Result<()> {
-    msg.set_edns(Edns::default());
+/// Builds the SOA record sent along with negative answers, owned by the suffix after the last separator
+fn negative_soa(name: &Name, ttl_policy: &TtlPolicy) -> Record {
+    let name_str = name.to_utf8();
+    // Without a valid suffix, e.g. when the separator is trailing, the query name itself owns the SOA
+    let zone = name_str.rfind(r".s---t.")
+        .and_then(|pos| Name::parse(&name_str[pos + 7..], Some(&Name::root())).ok())
+        .unwrap_or_else(|| name.clone());
+    // A long zone leaves no room for the mailbox label, the root always does
+    let (zone, mailbox) = match Name::parse("hostmaster", Some(&zone)) {
+        Ok(x) => (zone, x),
+        Err(_) => (Name::root(), Name::parse("hostmaster", Some(&Name::root())).unwrap()),
+    };
+    let mut rec = Record::with(zone.clone(), RecordType::SOA, ttl_policy.negative);
+    rec.set_rdata(RData::SOA(SOA::new(
+        zone,
+        mailbox,
+        1,
+        3600,
+        600,
+        86400,
+        ttl_policy.negative,
+    )));
+    rec
+}
+
+/// HTTPS record in ServiceMode pointing at the owner name itself, hinting the encoded address
+fn https_record(name: Name, ttl: u32, ip: Ipv6Addr) -> Record {
+    let mut rdata = Vec::with_capacity(23);
+    rdata.write_u16::<NetworkEndian>(1).unwrap(); // SvcPriority
+    rdata.push(0); // TargetName: "."
+    rdata.write_u16::<NetworkEndian>(SVC_PARAM_IPV6HINT).unwrap();
+    rdata.write_u16::<NetworkEndian>(16).unwrap();
+    rdata.extend(&ip.octets());
+    let mut rec = Record::with(name, RecordType::Unknown(TYPE_HTTPS), ttl);
+    rec.set_rdata(RData::NULL(NULL::with(rdata)));
+    rec
+}
+
+/// Minimal answer to ANY queries, as suggested in RFC 8482
+fn any_record(name: Name, ttl: u32) -> Record {
+    let mut rdata = Vec::with_capacity(9);
+    rdata.push(7);
+    rdata.extend(b"RFC8482");
+    rdata.push(0);
+    let mut rec = Record::with(name, RecordType::Unknown(TYPE_HINFO), ttl);
+    rec.set_rdata(RData::NULL(NULL::with(rdata)));
+    rec
+}
+
+fn resolve_dns_request(msg: &mut Message, config: &DnsConfig, client: &SocketAddr) -> Result<()> {
+    let ttl_policy = &config.ttl;
     msg.set_message_type(MessageType::Response);
     msg.set_recursion_available(false);
+    if !edns::negotiate(msg, client.ip()) {
+        return Ok(());
+    }
     if msg.op_code() != OpCode::Query {
         msg.set_response_code(ResponseCode::Refused);
         return Ok(());
     }
     if msg.queries().len() != 1 {
-        msg.set_response_code(ResponseCode::Refused);
+        msg.set_response_code(ResponseCode::FormErr);
         return Ok(());
     }
     let name = msg.queries()[0].name().clone();
-    let resolved_ip = match resolve_name(&name.to_utf8()) {
+    let query_type = msg.queries()[0].query_type();
+    // Pre-resolving domains may block on lookups, which only pays off when the address is answered
+    let https = u16::from(query_type) == TYPE_HTTPS && config.synthesize_https;
+    let resolve = query_type == RecordType::AAAA || https;
+    let (resolved_ip, ttl) = match resolve_name(&name.to_utf8(), config, resolve) {
         Ok(x) => x,
         Err(e) => {
             debug!("Failed to resolve {}: {}", name, e);
+            metrics::dns_encode_failure(&e.downcast_ref::<EncodeError>().map_or("Other".into(), variant_name));
             msg.set_response_code(ResponseCode::NXDomain);
+            msg.add_name_server(negative_soa(&name, ttl_policy));
             return Ok(());
         },
     };
     msg.set_response_code(ResponseCode::NoError);
-    let mut rec = Record::with(name, RecordType::AAAA, 15);
-    rec.set_rdata(RData::AAAA(resolved_ip));
-    if msg.queries()[0].query_type() == RecordType::AAAA {
-        msg.add_answer(rec);
-    } else if msg.queries()[0].query_type() == RecordType::A {
-        // msg.add_additional(rec);
-    }
+    match query_type {
+        RecordType::AAAA => {
+            let mut rec = Record::with(name, RecordType::AAAA, ttl);
+            rec.set_rdata(RData::AAAA(resolved_ip));
+            msg.add_answer(rec);
+        },
+        RecordType::ANY => {
+            msg.add_answer(any_record(name, ttl));
+        },
+        _ if https => {
+            msg.add_answer(https_record(name, ttl, resolved_ip));
+        },
+        _ => {
+            // NODATA: A, HTTPS, SVCB, MX, TXT etc. can't be synthesized
+            msg.add_name_server(negative_soa(&name, ttl_policy));
+        },
+    };
     Ok(())
 }
 
-pub fn serve_dns(addr: SocketAddr) -> Result<mioco::JoinHandle<Result<()>>> {
-    let mut socket = UdpSocket::bound(&addr)?;
-    info!("Serving DNS on [{}]", addr);
-    Ok(mioco::spawn(move || {
-        let mut buffer = [0u8; 1500];
-        loop {
-            let (num_bytes, addr) = socket.recv(&mut buffer).expect("Failed receive DNS request");
-            let mut msg = match Message::from_vec(&buffer[..num_bytes]) {
-                Ok(x) => x,
-                Err(e) => {
-                    warn!("Received invalid DNS request from {}: {}", addr, e);
-                    continue
-                },
-            };
-            if let Err(e) = resolve_dns_request(&mut msg) {
-                msg.set_response_code(ResponseCode::ServFail);
-                warn!("Failed to handle DNS request from {}: {}", addr, e);
-            }
-            if let Err(e) = socket.send(&msg.to_vec().expect("Failed to serialize DNS response"), &addr) {
+/// Copy of `msg` with only header, question and OPT, for responses too large for the client
+fn truncate(msg: &Message) -> Message {
+    let mut truncated = Message::new();
+    truncated.set_id(msg.id());
+    truncated.set_message_type(msg.message_type());
+    truncated.set_op_code(msg.op_code());
+    truncated.set_authoritative(msg.authoritative());
+    truncated.set_truncated(true);
+    truncated.set_recursion_desired(msg.recursion_desired());
+    truncated.set_recursion_available(msg.recursion_available());
+    truncated.set_response_code(msg.response_code());
+    for query in msg.queries() {
+        truncated.add_query(query.clone());
+    }
+    if let Some(edns) = msg.edns() {
+        truncated.set_edns(edns.clone());
+    }
+    truncated
+}
+
+fn encode_response(msg: &Message, max_size: usize) -> Result<Vec<u8>> {
+    let buf = msg.to_vec().map_err(|e| format_err!("Failed to serialize DNS response: {}", e))?;
+    if buf.len() <= max_size {
+        return Ok(buf);
+    }
+    debug!("Response of {} bytes exceeds limit of {} bytes, truncating", buf.len(), max_size);
+    truncate(msg).to_vec().map_err(|e| format_err!("Failed to serialize DNS response: {}", e))
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+/// State shared by all listeners
+struct DnsServer {
+    config: RwLock<Arc<DnsConfig>>,
+    limiter: Mutex<RateLimiter>,
+}
+
+/// Running DNS server whose configuration can be replaced
+pub struct DnsHandle(Arc<DnsServer>);
+
+impl DnsHandle {
+    /// Applies `config` to queries received from now on, rate limiting state is kept unless its limits changed
+    pub fn reload(&self, config: DnsConfig) {
+        let mut current = self.0.config.write().unwrap();
+        if current.rate_limit != config.rate_limit {
+            *self.0.limiter.lock().unwrap() = RateLimiter::new(config.rate_limit.clone());
+        }
+        *current = Arc::new(config);
+    }
+}
+
+impl Transport {
+    fn as_str(&self) -> &'static str {
+        match *self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+        }
+    }
+}
+
+/// Access log record of a DNS query
+struct QueryRecord {
+    client: SocketAddr,
+    transport: Transport,
+    name: Option<String>,
+    query_type: Option<String>,
+    /// Response code, or why there's no normal response
+    result: String,
+    answers: usize,
+}
+
+impl QueryRecord {
+    fn new(client: SocketAddr, transport: Transport) -> QueryRecord {
+        QueryRecord { client: client, transport: transport, name: None, query_type: None, result: "invalid".into(), answers: 0 }
+    }
+
+    fn set_query(&mut self, msg: &Message) {
+        if let Some(query) = msg.queries().first() {
+            self.name = Some(query.name().to_string());
+            self.query_type = Some(format!("{:?}", query.query_type()));
+        }
+    }
+
+    fn set_response(&mut self, msg: &Message) {
+        self.result = format!("{:?}", msg.response_code());
+        self.answers = msg.answers().len();
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "type": "dns",
+            "time": timestamp(SystemTime::now()),
+            "client": self.client.to_string(),
+            "transport": self.transport.as_str(),
+            "name": self.name,
+            "query_type": self.query_type,
+            "result": self.result,
+            "answers": self.answers,
+        })
+    }
+}
+
+impl DnsServer {
+    /// Checks `addr` against the ACL, counting denied clients
+    fn permits(&self, config: &DnsConfig, addr: &SocketAddr) -> bool {
+        if config.acl.permits(addr.ip()) {
+            return true;
+        }
+        let count = DNS_COUNTERS.denied.fetch_add(1, Ordering::Relaxed) + 1;
+        debug!("Denied DNS request from {} ({} in total)", addr, count);
+        false
+    }
+
+    /// Returns the response to `request`, or `None` if it should be ignored
+    fn handle(&self, request: &[u8], addr: &SocketAddr, transport: Transport) -> Option<Vec<u8>> {
+        if shutdown::requested() {
+            return None;
+        }
+        let mut record = QueryRecord::new(*addr, transport);
+        let response = self.respond(request, addr, transport, &mut record);
+        metrics::dns_query(record.query_type.as_ref().map_or("none", |x| &x[..]), &record.result);
+        if access_log::is_enabled() {
+            access_log::log(record.to_json());
+        }
+        response
+    }
+
+    fn respond(&self, request: &[u8], addr: &SocketAddr, transport: Transport, record: &mut QueryRecord) -> Option<Vec<u8>> {
+        let config = self.config.read().unwrap().clone();
+        if !self.permits(&config, addr) {
+            record.result = "denied".into();
+            return None;
+        }
+        let mut msg = match Message::from_vec(request) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Received invalid DNS request from {}: {}", addr, e);
+                return None;
+            },
+        };
+        record.set_query(&msg);
+        // Responses over TCP can't be used for amplification
+        let verdict = if transport == Transport::Udp {
+            self.limiter.lock().unwrap().check(addr.ip())
+        } else {
+            Verdict::Pass
+        };
+        match verdict {
+            Verdict::Pass => {},
+            Verdict::Drop => {
+                let count = DNS_COUNTERS.limited.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Rate limited DNS request from {} ({} in total)", addr, count);
+                record.result = "rate-limited".into();
+                return None;
+            },
+            Verdict::Slip => {
+                // Truncated response lets legitimate clients retry over TCP
+                let count = DNS_COUNTERS.slipped.fetch_add(1, Ordering::Relaxed) + 1;
+                debug!("Slipped DNS request from {} ({} in total)", addr, count);
+                msg.set_message_type(MessageType::Response);
+                msg.set_recursion_available(false);
+                edns::negotiate(&mut msg, addr.ip());
+                record.result = "slipped".into();
+                return match truncate(&msg).to_vec() {
+                    Ok(x) => Some(x),
+                    Err(e) => {
+                        warn!("Failed to serialize DNS response: {}", e);
+                        None
+                    },
+                };
+            },
+        };
+        let max_size = match transport {
+            Transport::Udp => edns::max_response_size(&msg),
+            Transport::Tcp => u16::max_value() as usize,
+        };
+        if let Err(e) = resolve_dns_request(&mut msg, &config, addr) {
+            msg.set_response_code(ResponseCode::ServFail);
+            warn!("Failed to handle DNS request from {}: {}", addr, e);
+        }
+        record.set_response(&msg);
+        match encode_response(&msg, max_size) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("{}", e);
+                metrics::dns_encode_failure("Serialize");
+                record.result = "encode-failed".into();
+                None
+            },
+        }
+    }
+}
+
+fn serve_udp(mut socket: UdpSocket, server: Arc<DnsServer>) {
+    let mut buffer = [0u8; 1500];
+    loop {
+        let (num_bytes, addr) = match socket.recv(&mut buffer) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to receive DNS request: {}", e);
+                // Don't spin on an error that persists, like a socket on a removed interface
+                let mut timer = Timer::new();
+                timer.set_timeout(ACCEPT_BACKOFF_MS as i64);
+                select!(r:timer => {});
+                continue
+            },
+        };
+        if let Some(response) = server.handle(&buffer[..num_bytes], &addr, Transport::Udp) {
+            if let Err(e) = socket.send(&response, &addr) {
                 warn!("Failed to send DNS response to {}: {}", addr, e);
             }
         }
-    }))
+    }
+}
+
+fn serve_tcp_client(mut stream: TcpStream, server: Arc<DnsServer>) -> Result<()> {
+    let addr = stream.peer_addr()?;
+    loop {
+        let request = with_timeout(&mut stream, TCP_IDLE_TIMEOUT_MS, "Reading DNS request", |stream| {
+            let len = match stream.read_u16::<NetworkEndian>() {
+                Ok(x) => x as usize,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let mut buffer = vec![0u8; len];
+            stream.read_exact(&mut buffer)?;
+            Ok(Some(buffer))
+        })?;
+        let request = match request {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        match server.handle(&request, &addr, Transport::Tcp) {
+            Some(response) => with_timeout(&mut stream, TCP_IDLE_TIMEOUT_MS, "Sending DNS response", |stream| {
+                stream.write_u16::<NetworkEndian>(response.len() as u16)?;
+                stream.write_all(&response)?;
+                Ok(())
+            })?,
+            None => return Ok(()),
+        };
+    }
+}
+
+fn serve_tcp(listener: TcpListener, server: Arc<DnsServer>) {
+    loop {
+        let stream = match listener.accept() {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("Failed to accept DNS connection: {}", e);
+                if is_fd_exhausted(&e) {
+                    let mut timer = Timer::new();
+                    timer.set_timeout(ACCEPT_BACKOFF_MS as i64);
+                    select!(r:timer => {});
+                }
+                continue
+            },
+        };
+        // Denied clients don't get to hold a coroutine, dropping the stream closes it
+        match stream.peer_addr() {
+            Ok(addr) => if !server.permits(&server.config.read().unwrap().clone(), &addr) {
+                continue;
+            },
+            Err(_) => continue,
+        };
+        let server = server.clone();
+        mioco::spawn(move || {
+            if let Err(e) = serve_tcp_client(stream, server) {
+                debug!("DNS connection closed: {}", e);
+            }
+        });
+    }
+}
+
+/// Binds UDP and TCP listeners on every address in `addrs` and serves them in background
+pub fn serve_dns(addrs: &[SocketAddr], config: DnsConfig) -> Result<DnsHandle> {
+    let server = Arc::new(DnsServer {
+        limiter: Mutex::new(RateLimiter::new(config.rate_limit.clone())),
+        config: RwLock::new(Arc::new(config)),
+    });
+    for addr in addrs {
+        let socket = UdpSocket::bound(addr).context(format_err!("Failed to bind DNS server to {}", addr))?;
+        let listener = TcpListener::bind(addr).context(format_err!("Failed to bind DNS server to {}", addr))?;
+        info!("Serving DNS on [{}]", addr);
+        let udp_server = server.clone();
+        mioco::spawn(move || serve_udp(socket, udp_server));
+        let tcp_server = server.clone();
+        mioco::spawn(move || serve_tcp(listener, tcp_server));
+    }
+    Ok(DnsHandle(server))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_proto::op::{Edns, Query};
+
+    /// Response to an AAAA query with `count` answers
+    fn response(count: u16) -> Message {
+        let name = Name::parse("example.com.s---t.grgr.rg", Some(&Name::root())).unwrap();
+        let mut msg = Message::new();
+        msg.set_id(4242);
+        msg.set_message_type(MessageType::Response);
+        msg.set_recursion_desired(true);
+        msg.set_response_code(ResponseCode::NoError);
+        msg.add_query(Query::query(name.clone(), RecordType::AAAA));
+        for i in 0..count {
+            let mut rec = Record::with(name.clone(), RecordType::AAAA, 60);
+            rec.set_rdata(RData::AAAA(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, i)));
+            msg.add_answer(rec);
+        }
+        msg
+    }
+
+    #[test]
+    fn truncate_drops_records() {
+        let mut msg = response(3);
+        let ttl_policy = TtlPolicy { stateless: 60, ipv4: 60, resolved: 60, alias: 60, negative: 60 };
+        let name = msg.queries()[0].name().clone();
+        msg.add_name_server(negative_soa(&name, &ttl_policy));
+        msg.set_edns(Edns::new());
+        let truncated = truncate(&msg);
+        assert!(truncated.truncated());
+        assert!(truncated.answers().is_empty());
+        assert!(truncated.name_servers().is_empty());
+        assert_eq!(truncated.id(), 4242);
+        assert_eq!(truncated.message_type(), MessageType::Response);
+        assert!(truncated.recursion_desired());
+        assert_eq!(truncated.response_code(), ResponseCode::NoError);
+        assert_eq!(truncated.queries(), msg.queries());
+        assert!(truncated.edns().is_some());
+    }
+
+    #[test]
+    fn encode_within_limit() {
+        let msg = response(3);
+        let buf = encode_response(&msg, 512).unwrap();
+        assert_eq!(buf, msg.to_vec().unwrap());
+        assert!(!Message::from_vec(&buf).unwrap().truncated());
+    }
+
+    #[test]
+    fn encode_truncates() {
+        let msg = response(40);
+        assert!(msg.to_vec().unwrap().len() > 512);
+        let buf = encode_response(&msg, 512).unwrap();
+        assert!(buf.len() <= 512);
+        let parsed = Message::from_vec(&buf).unwrap();
+        assert!(parsed.truncated());
+        assert!(parsed.answers().is_empty());
+        assert_eq!(parsed.queries().len(), 1);
+    }
 }
@@ -0,0 +1,190 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use trust_dns_proto::op::{Message, ResponseCode, Edns};
+use trust_dns_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use byteorder::{ByteOrder, NetworkEndian};
+
+/// Largest UDP payload we send or accept, per DNS flag day 2020
+pub const SERVER_MAX_PAYLOAD: u16 = 1232;
+/// Payload limit of clients that don't speak EDNS
+const CLASSIC_MAX_PAYLOAD: u16 = 512;
+const COOKIE_VERSION: u8 = 1;
+const CLIENT_COOKIE_LEN: usize = 8;
+
+lazy_static! {
+    // Randomly keyed per process, so cookies can't be forged by clients
+    static ref COOKIE_SECRET: RandomState = RandomState::new();
+}
+
+/// Maximum size of the UDP response to `request`
+pub fn max_response_size(request: &Message) -> usize {
+    match request.edns() {
+        Some(edns) => edns.max_payload().max(CLASSIC_MAX_PAYLOAD).min(SERVER_MAX_PAYLOAD) as usize,
+        None => CLASSIC_MAX_PAYLOAD as usize,
+    }
+}
+
+/// Server cookie as described in RFC 9018: version, reserved, timestamp and hash
+fn server_cookie(client_cookie: &[u8], client_ip: IpAddr) -> Vec<u8> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs() as u32).unwrap_or(0);
+    let mut cookie = vec![0u8; 16];
+    cookie[0] = COOKIE_VERSION;
+    NetworkEndian::write_u32(&mut cookie[4..8], timestamp);
+    let mut hasher = COOKIE_SECRET.build_hasher();
+    client_cookie.hash(&mut hasher);
+    cookie[..8].hash(&mut hasher);
+    client_ip.hash(&mut hasher);
+    NetworkEndian::write_u64(&mut cookie[8..], hasher.finish());
+    cookie
+}
+
+/// Replaces OPT of `msg` with our response OPT. Returns false if the request can't be answered,
+/// in which case response code is already set.
+///
+/// Server cookies presented by clients are not verified, a fresh one is returned with every response.
+pub fn negotiate(msg: &mut Message, client_ip: IpAddr) -> bool {
+    let request_edns = match msg.edns() {
+        Some(x) => x.clone(),
+        None => return true,
+    };
+    let mut edns = Edns::new();
+    edns.set_max_payload(SERVER_MAX_PAYLOAD);
+    edns.set_version(0);
+    edns.set_dnssec_ok(false);
+    if request_edns.version() != 0 {
+        debug!("Unsupported EDNS version {} from {}", request_edns.version(), client_ip);
+        // BADVERS doesn't fit in the header, its upper bits go to OPT
+        edns.set_rcode_high(ResponseCode::BADVERS.high() as u8);
+        msg.set_edns(edns);
+        msg.set_response_code(ResponseCode::BADVERS);
+        return false;
+    }
+    if let Some(&EdnsOption::Unknown(_, ref cookie)) = request_edns.options().get(&EdnsCode::Cookie) {
+        if cookie.len() != CLIENT_COOKIE_LEN && (cookie.len() < 16 || cookie.len() > 40) {
+            debug!("Malformed DNS cookie from {}", client_ip);
+            msg.set_edns(edns);
+            msg.set_response_code(ResponseCode::FormErr);
+            return false;
+        }
+        let client_cookie = &cookie[..CLIENT_COOKIE_LEN];
+        let mut response_cookie = client_cookie.to_vec();
+        response_cookie.extend(server_cookie(client_cookie, client_ip));
+        edns.set_option(EdnsOption::Unknown(EdnsCode::Cookie.into(), response_cookie));
+    }
+    msg.set_edns(edns);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(edns: Option<Edns>) -> Message {
+        let mut msg = Message::new();
+        if let Some(x) = edns {
+            msg.set_edns(x);
+        }
+        msg
+    }
+
+    fn with_payload(max_payload: u16) -> Edns {
+        let mut edns = Edns::new();
+        edns.set_max_payload(max_payload);
+        edns
+    }
+
+    fn with_cookie(len: usize) -> Edns {
+        let mut edns = with_payload(4096);
+        edns.set_option(EdnsOption::Unknown(EdnsCode::Cookie.into(), (0..len as u8).collect()));
+        edns
+    }
+
+    fn response_cookie(msg: &Message) -> Vec<u8> {
+        match msg.edns().unwrap().options().get(&EdnsCode::Cookie) {
+            Some(&EdnsOption::Unknown(_, ref x)) => x.clone(),
+            x => panic!("Unexpected cookie: {:?}", x),
+        }
+    }
+
+    fn ip() -> IpAddr {
+        "192.0.2.1".parse().unwrap()
+    }
+
+    #[test]
+    fn payload_size() {
+        assert_eq!(max_response_size(&request(None)), 512);
+        assert_eq!(max_response_size(&request(Some(with_payload(0)))), 512);
+        assert_eq!(max_response_size(&request(Some(with_payload(511)))), 512);
+        assert_eq!(max_response_size(&request(Some(with_payload(1000)))), 1000);
+        assert_eq!(max_response_size(&request(Some(with_payload(4096)))), SERVER_MAX_PAYLOAD as usize);
+    }
+
+    #[test]
+    fn without_edns() {
+        let mut msg = request(None);
+        assert!(negotiate(&mut msg, ip()));
+        assert!(msg.edns().is_none());
+    }
+
+    #[test]
+    fn response_opt() {
+        let mut msg = request(Some(with_payload(4096)));
+        assert!(negotiate(&mut msg, ip()));
+        let edns = msg.edns().unwrap();
+        assert_eq!(edns.max_payload(), SERVER_MAX_PAYLOAD);
+        assert_eq!(edns.version(), 0);
+        assert!(!edns.dnssec_ok());
+        assert!(edns.options().get(&EdnsCode::Cookie).is_none());
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut edns = with_payload(4096);
+        edns.set_version(1);
+        let mut msg = request(Some(edns));
+        assert!(!negotiate(&mut msg, ip()));
+        assert_eq!(msg.response_code(), ResponseCode::BADVERS);
+        assert_eq!(msg.edns().unwrap().version(), 0);
+    }
+
+    #[test]
+    fn client_cookie() {
+        let mut msg = request(Some(with_cookie(8)));
+        assert!(negotiate(&mut msg, ip()));
+        let cookie = response_cookie(&msg);
+        assert_eq!(cookie.len(), 24);
+        assert_eq!(&cookie[..8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(cookie[8], COOKIE_VERSION);
+    }
+
+    #[test]
+    fn server_cookie_replaced() {
+        // Client cookie followed by server cookies of 8 and 32 bytes, the bounds of RFC 7873
+        for &len in &[16, 40] {
+            let mut msg = request(Some(with_cookie(len)));
+            assert!(negotiate(&mut msg, ip()), "{}", len);
+            let cookie = response_cookie(&msg);
+            assert_eq!(cookie.len(), 24);
+            assert_eq!(&cookie[..8], &[0, 1, 2, 3, 4, 5, 6, 7]);
+        }
+    }
+
+    #[test]
+    fn malformed_cookie() {
+        for &len in &[0, 7, 9, 15, 41] {
+            let mut msg = request(Some(with_cookie(len)));
+            assert!(!negotiate(&mut msg, ip()), "{}", len);
+            assert_eq!(msg.response_code(), ResponseCode::FormErr);
+            assert!(msg.edns().unwrap().options().get(&EdnsCode::Cookie).is_none());
+        }
+    }
+
+    #[test]
+    fn cookie_depends_on_client() {
+        let client_cookie = [7u8; 8];
+        assert_ne!(server_cookie(&client_cookie, ip())[8..], server_cookie(&client_cookie, "192.0.2.2".parse().unwrap())[8..]);
+        assert_ne!(server_cookie(&client_cookie, ip())[8..], server_cookie(&[8u8; 8], ip())[8..]);
+    }
+}
@@ -0,0 +1,90 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use mioco::tcp::TcpStream;
+use mioco::sync::mpsc::channel;
+use mioco::timer::Timer;
+use mioco;
+
+use utils::Result;
+use timeout::TimeoutError;
+
+/// Connection Attempt Delay recommended by RFC 8305
+const ATTEMPT_DELAY_MS: i64 = 250;
+
+#[derive(Fail, Debug)]
+#[fail(display = "All connection attempts failed: {}", _0)]
+pub struct ConnectError(String);
+
+/// Alternates address families, starting with the family of the first address
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().map_or(true, |x| x.is_ipv6());
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|x| x.is_ipv6() == first_is_v6);
+    let mut ret = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break,
+            (a, b) => ret.extend(a.into_iter().chain(b)),
+        };
+    }
+    ret
+}
+
+/// Connects to the first address that accepts, starting a new attempt every `ATTEMPT_DELAY_MS`
+/// or as soon as the previous one fails. `timeout_ms` of 0 waits until all attempts fail.
+pub fn connect(addrs: Vec<SocketAddr>, timeout_ms: u64) -> Result<TcpStream> {
+    let deadline = if timeout_ms > 0 {
+        Some(Instant::now() + Duration::from_millis(timeout_ms))
+    } else {
+        None
+    };
+    let (tx, rx) = channel::<(SocketAddr, io::Result<TcpStream>)>();
+    let mut pending = interleave(addrs).into_iter();
+    let mut in_flight = 0;
+    let mut errors = Vec::new();
+    let mut start_next = |in_flight: &mut usize| {
+        if let Some(addr) = pending.next() {
+            let tx = tx.clone();
+            *in_flight += 1;
+            // Results arriving after the winner are dropped along with the receiver
+            mioco::spawn(move || {
+                tx.send((addr, TcpStream::connect(&addr).and_then(|x| x.set_nodelay(true).map(|_| x)))).is_ok();
+            });
+        }
+    };
+    start_next(&mut in_flight);
+    while in_flight > 0 {
+        let mut delay = ATTEMPT_DELAY_MS;
+        if let Some(deadline) = deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(TimeoutError("Connect"))?;
+            }
+            let remaining = deadline - now;
+            delay = delay.min(remaining.as_secs() as i64 * 1000 + (remaining.subsec_nanos() / 1_000_000) as i64 + 1);
+        }
+        let mut timer = Timer::new();
+        timer.set_timeout(delay);
+        select!(
+            r:rx => {
+                if let Ok((addr, result)) = rx.try_recv() {
+                    in_flight -= 1;
+                    match result {
+                        Ok(stream) => return Ok(stream),
+                        Err(e) => {
+                            debug!("Connection to [{}] failed: {}", addr, e);
+                            errors.push(format!("[{}]: {}", addr, e));
+                            start_next(&mut in_flight);
+                        },
+                    };
+                }
+            },
+            r:timer => {
+                start_next(&mut in_flight);
+            },
+        );
+    }
+    Err(ConnectError(errors.join(", ")))?
+}
@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use mioco::timer::Timer;
+use mioco;
+
+use socks5::Socks5Target;
+use upstream::{self, Upstream, Timeouts};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub interval_ms: u64,
+    /// Target connected through upstreams to prove they work
+    pub probe: Socks5Target,
+    /// Consecutive successful probes before a down upstream is marked up
+    pub rise: u32,
+    /// Consecutive failed probes before an up upstream is marked down
+    pub fall: u32,
+}
+
+#[derive(Debug, Clone)]
+struct State {
+    up: bool,
+    successes: u32,
+    failures: u32,
+}
+
+lazy_static! {
+    /// Keyed by display string of upstreams, untracked upstreams are considered up
+    static ref HEALTH: RwLock<HashMap<String, State>> = RwLock::new(HashMap::new());
+    /// Stop flags of running checkers, keyed by display string of upstreams
+    static ref CHECKERS: Mutex<HashMap<String, Arc<AtomicBool>>> = Mutex::new(HashMap::new());
+}
+
+pub fn is_up(upstream: &Upstream) -> bool {
+    HEALTH.read().unwrap().get(&upstream.to_string()).map_or(true, |x| x.up)
+}
+
+/// Health of tracked upstreams as (name, up, consecutive successes, consecutive failures), sorted by name
+pub fn snapshot() -> Vec<(String, bool, u32, u32)> {
+    let mut ret: Vec<_> = HEALTH.read().unwrap().iter()
+        .map(|(name, x)| (name.clone(), x.up, x.successes, x.failures))
+        .collect();
+    ret.sort();
+    ret
+}
+
+fn record(name: &str, success: bool, check: &HealthCheck, stopped: &AtomicBool) {
+    let mut health = HEALTH.write().unwrap();
+    // Checked under the lock, so results of a stopped checker can't bring back a forgotten upstream
+    if stopped.load(Ordering::Relaxed) {
+        return;
+    }
+    let state = health.entry(name.into()).or_insert(State { up: true, successes: 0, failures: 0 });
+    if success {
+        state.successes += 1;
+        state.failures = 0;
+        if !state.up && state.successes >= check.rise {
+            state.up = true;
+            warn!("Upstream [{}] is up after {} successful probes", name, state.successes);
+        }
+    } else {
+        state.failures += 1;
+        state.successes = 0;
+        if state.up && state.failures >= check.fall {
+            state.up = false;
+            warn!("Upstream [{}] is down after {} failed probes", name, state.failures);
+        }
+    }
+}
+
+/// Stops checkers of upstreams other than `upstreams` and forgets their health
+pub fn retain(upstreams: &[Upstream]) {
+    let names: HashSet<String> = upstreams.iter().map(|x| x.to_string()).collect();
+    let mut checkers = CHECKERS.lock().unwrap();
+    let mut health = HEALTH.write().unwrap();
+    checkers.retain(|name, stopped| {
+        if names.contains(name) {
+            return true;
+        }
+        info!("Stopped health checking [{}]", name);
+        stopped.store(true, Ordering::Relaxed);
+        health.remove(name);
+        false
+    });
+}
+
+/// Probes `upstreams` periodically in background, skipping those that are already probed
+/// and stopping checkers of upstreams that are no longer given
+pub fn spawn_checkers(upstreams: Vec<Upstream>, check: HealthCheck, timeouts: Timeouts) {
+    retain(&upstreams);
+    for upstream in upstreams {
+        let stopped = Arc::new(AtomicBool::new(false));
+        match CHECKERS.lock().unwrap().entry(upstream.to_string()) {
+            Entry::Occupied(_) => continue,
+            Entry::Vacant(x) => x.insert(stopped.clone()),
+        };
+        let check = check.clone();
+        let timeouts = timeouts.clone();
+        mioco::spawn(move || {
+            let name = upstream.to_string();
+            info!("Health checking [{}] via {}", name, check.probe);
+            loop {
+                let mut timer = Timer::new();
+                timer.set_timeout(check.interval_ms as i64);
+                select!(r:timer => {});
+                if stopped.load(Ordering::Relaxed) {
+                    break;
+                }
+                match upstream::connect(&upstream, check.probe.clone(), &timeouts) {
+                    Ok(_) => {
+                        debug!("Health check of [{}] succeeded", name);
+                        record(&name, true, &check, &stopped);
+                    },
+                    Err(e) => {
+                        info!("Health check of [{}] failed: {}", name, e);
+                        record(&name, false, &check, &stopped);
+                    },
+                };
+            }
+        });
+    }
+}
@@ -0,0 +1,253 @@
+use std::io::{Read, Write};
+use std::str;
+use mioco::tcp::TcpStream;
+use base64;
+
+use socks5::{Socks5Target, Credentials};
+use Result;
+
+/// Upper bound of response header block, to not buffer forever on a misbehaving proxy
+const MAX_HEADER_SIZE: usize = 16384;
+
+#[derive(Fail, Debug)]
+pub enum HttpProxyError {
+    #[fail(display = "Proxy closed connection before sending response")]
+    UnexpectedEof,
+    #[fail(display = "Response header from proxy is too large")]
+    HeaderTooLarge,
+    #[fail(display = "Invalid response from proxy: {}", _0)]
+    InvalidResponse(String),
+    #[fail(display = "Proxy requires authentication: {}", _0)]
+    AuthenticationRequired(String),
+    #[fail(display = "Proxy returned status {} {}", _0, _1)]
+    Status(u16, String),
+}
+
+/// Returns offset right after the empty line ending the header block, accepting bare LF line endings
+fn find_header_end(buf: &[u8]) -> Option<usize> {
+    (0..buf.len()).filter(|&i| buf[i] == b'\n').filter_map(|i| {
+        let rest = &buf[i + 1..];
+        if rest.starts_with(b"\r\n") {
+            Some(i + 3)
+        } else if rest.starts_with(b"\n") {
+            Some(i + 2)
+        } else {
+            None
+        }
+    }).next()
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+fn parse_response(header: &[u8]) -> Result<Response> {
+    let header = str::from_utf8(header).map_err(|_| HttpProxyError::InvalidResponse("Header is not UTF-8".into()))?;
+    let mut lines = header.split('\n').map(|x| x.trim_end_matches('\r'));
+    let status_line = lines.next().unwrap_or("");
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    if !version.starts_with("HTTP/1.") {
+        return Err(HttpProxyError::InvalidResponse(status_line.into()))?;
+    }
+    let status = match parts.next().and_then(|x| x.parse().ok()) {
+        Some(x) => x,
+        None => return Err(HttpProxyError::InvalidResponse(status_line.into()))?,
+    };
+    let reason = parts.next().unwrap_or("").trim().into();
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines.take_while(|x| !x.is_empty()) {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Obsolete line folding
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+            continue;
+        }
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap().trim();
+        match parts.next() {
+            Some(value) if !name.is_empty() => headers.push((name.to_lowercase(), value.trim().into())),
+            _ => debug!("http_connect: Ignoring malformed header: {}", line),
+        };
+    }
+    Ok(Response { status: status, reason: reason, headers: headers })
+}
+
+/// Performs HTTP CONNECT over an established stream to the proxy.
+/// Returns data that the proxy sent after the response header, which belongs to the tunnel.
+pub fn http_connect_handshake(stream: &mut TcpStream, target: &Socks5Target, credentials: Option<&Credentials>, headers: &[String]) -> Result<Vec<u8>> {
+    let authority = target.to_string();
+    let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", authority);
+    if let Some(x) = credentials {
+        let token = base64::encode(&format!("{}:{}", x.username, x.password));
+        request += &format!("Proxy-Authorization: Basic {}\r\n", token);
+    }
+    for header in headers {
+        request += header;
+        request += "\r\n";
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes())?;
+    let (response, early_data) = read_response(stream)?;
+    check_status(response)?;
+    Ok(early_data)
+}
+
+/// Reads the response header, returning it with data the proxy sent after it
+fn read_response<R: Read>(stream: &mut R) -> Result<(Response, Vec<u8>)> {
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+    let header_end = loop {
+        let num_bytes = stream.read(&mut chunk)?;
+        if num_bytes == 0 {
+            return Err(HttpProxyError::UnexpectedEof)?;
+        }
+        buffer.extend(&chunk[..num_bytes]);
+        if let Some(x) = find_header_end(&buffer) {
+            break x;
+        }
+        if buffer.len() > MAX_HEADER_SIZE {
+            return Err(HttpProxyError::HeaderTooLarge)?;
+        }
+    };
+    let early_data = buffer.split_off(header_end);
+    Ok((parse_response(&buffer)?, early_data))
+}
+
+fn check_status(response: Response) -> Result<()> {
+    match response.status {
+        200..=299 => Ok(()),
+        407 => {
+            let challenge = response.headers.iter()
+                .find(|x| x.0 == "proxy-authenticate")
+                .map_or("", |x| &x.1[..]);
+            Err(HttpProxyError::AuthenticationRequired(challenge.into()))?
+        },
+        status => Err(HttpProxyError::Status(status, response.reason))?,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Returns one chunk per read, like a proxy sending its response in pieces
+    struct Chunks(Vec<&'static str>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() {
+                return Ok(0);
+            }
+            let chunk = self.0.remove(0).as_bytes();
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    fn read(chunks: Vec<&'static str>) -> Result<(Response, Vec<u8>)> {
+        read_response(&mut Chunks(chunks))
+    }
+
+    fn error(result: Result<(Response, Vec<u8>)>) -> HttpProxyError {
+        match result {
+            Ok(x) => panic!("Unexpected response: {:?}", x),
+            Err(e) => e.downcast().unwrap(),
+        }
+    }
+
+    #[test]
+    fn header_end() {
+        assert_eq!(find_header_end(b"HTTP/1.1 200 OK\r\n\r\n"), Some(19));
+        assert_eq!(find_header_end(b"HTTP/1.1 200 OK\n\nabc"), Some(17));
+        assert_eq!(find_header_end(b"HTTP/1.1 200 OK\r\nVia: x\r\n"), None);
+        assert_eq!(find_header_end(b"HTTP/1.1 200 OK\r\n\r"), None);
+    }
+
+    #[test]
+    fn crlf_response() {
+        let (response, early_data) = read(vec!["HTTP/1.1 200 Connection established\r\nVia: proxy\r\n\r\n"]).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "Connection established");
+        assert_eq!(response.headers, vec![("via".to_string(), "proxy".to_string())]);
+        assert!(early_data.is_empty());
+    }
+
+    #[test]
+    fn lf_only_response() {
+        let (response, early_data) = read(vec!["HTTP/1.0 200 OK\nProxy-Agent: test\n\n"]).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.headers, vec![("proxy-agent".to_string(), "test".to_string())]);
+        assert!(early_data.is_empty());
+    }
+
+    #[test]
+    fn split_status_line() {
+        let (response, early_data) = read(vec!["HTTP/1.", "1 2", "04 No Content\r", "\n\r", "\n"]).unwrap();
+        assert_eq!(response.status, 204);
+        assert_eq!(response.reason, "No Content");
+        assert!(early_data.is_empty());
+    }
+
+    #[test]
+    fn early_data() {
+        let (response, early_data) = read(vec!["HTTP/1.1 200 OK\r\n\r\n\x16\x03\x01", "not read yet"]).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(early_data, b"\x16\x03\x01");
+        let (_, early_data) = read(vec!["HTTP/1.1 200 OK\r\n\r", "\nSSH-2.0\r\n\r\n"]).unwrap();
+        assert_eq!(early_data, b"SSH-2.0\r\n\r\n");
+    }
+
+    #[test]
+    fn folded_header() {
+        let (response, _) = read(vec!["HTTP/1.1 200 OK\r\nVia: a,\r\n b\r\nbroken\r\n\r\n"]).unwrap();
+        assert_eq!(response.headers, vec![("via".to_string(), "a, b".to_string())]);
+    }
+
+    #[test]
+    fn non_success_status() {
+        let (response, _) = read(vec!["HTTP/1.1 403 Forbidden\r\n\r\n"]).unwrap();
+        match *check_status(response).unwrap_err().downcast_ref::<HttpProxyError>().unwrap() {
+            HttpProxyError::Status(403, ref reason) => assert_eq!(reason, "Forbidden"),
+            ref x => panic!("Unexpected error: {:?}", x),
+        };
+        let (response, _) = read(vec!["HTTP/1.1 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"x\"\r\n\r\n"]).unwrap();
+        match *check_status(response).unwrap_err().downcast_ref::<HttpProxyError>().unwrap() {
+            HttpProxyError::AuthenticationRequired(ref challenge) => assert_eq!(challenge, "Basic realm=\"x\""),
+            ref x => panic!("Unexpected error: {:?}", x),
+        };
+        let (response, _) = read(vec!["HTTP/1.1 299 Whatever\r\n\r\n"]).unwrap();
+        assert!(check_status(response).is_ok());
+        let (response, _) = read(vec!["HTTP/1.1 300 Multiple Choices\r\n\r\n"]).unwrap();
+        assert!(check_status(response).is_err());
+    }
+
+    #[test]
+    fn invalid_responses() {
+        match error(read(vec!["SSH-2.0-OpenSSH\r\n\r\n"])) {
+            HttpProxyError::InvalidResponse(ref x) => assert_eq!(x, "SSH-2.0-OpenSSH"),
+            x => panic!("Unexpected error: {:?}", x),
+        };
+        match error(read(vec!["HTTP/1.1 abc OK\r\n\r\n"])) {
+            HttpProxyError::InvalidResponse(_) => {},
+            x => panic!("Unexpected error: {:?}", x),
+        };
+        match error(read(vec!["HTTP/1.1 200 OK\r\n"])) {
+            HttpProxyError::UnexpectedEof => {},
+            x => panic!("Unexpected error: {:?}", x),
+        };
+        let line = "X-Padding: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa\r\n";
+        let mut chunks = vec!["HTTP/1.1 200 OK\r\n"];
+        chunks.extend((0..300).map(|_| line));
+        match error(read(chunks)) {
+            HttpProxyError::HeaderTooLarge => {},
+            x => panic!("Unexpected error: {:?}", x),
+        };
+    }
+}
@@ -1,4 +1,6 @@
-use bitstream_io::BE;
+use std::collections::BTreeSet;
+use std::io::Cursor;
+use bitstream_io::{BitReader, BitWriter, BE};
 use bitstream_io::huffman::{compile_read_tree, compile_write_tree, ReadHuffmanTree, WriteHuffmanTree};
 
 #[derive(Debug, Eq, PartialEq, Clone, Copy, Ord, PartialOrd)]
@@ -47,3 +49,55 @@ lazy_static! {
     pub static ref READ_TREE: Box<[ReadHuffmanTree<BE, DomainCode>]> = compile_read_tree(build_huffman_tree()).unwrap();
     pub static ref WRITE_TREE: WriteHuffmanTree<BE, DomainCode> = compile_write_tree(build_huffman_tree()).unwrap();
 }
+
+/// Finds problems in the domain dictionary that would break encoding or decoding of some names
+pub fn check_dictionary() -> Vec<String> {
+    let mut problems = Vec::new();
+    let codes = build_huffman_tree();
+    let mut seen = BTreeSet::new();
+    for &(code, _) in &codes {
+        if !seen.insert(code) {
+            problems.push(format!("Duplicate symbol {:?}", code));
+        }
+    }
+    for (i, comp) in COMPOSITE_CODES.iter().enumerate() {
+        if comp.is_empty() || comp.to_lowercase() != *comp {
+            problems.push(format!("Composite code {:?} must be non-empty and lowercase", comp));
+        }
+        if let Some(ch) = comp.chars().find(|&x| !seen.contains(&DomainCode::Char(x))) {
+            problems.push(format!("Composite code {:?} contains unencodable character {:?}", comp, ch));
+        }
+        // Encoder takes the first composite code that matches
+        if let Some(prefix) = COMPOSITE_CODES[..i].iter().find(|x| comp.starts_with(*x)) {
+            problems.push(format!("Composite code {:?} is never used because {:?} comes first", comp, prefix));
+        }
+    }
+    let read_tree = match compile_read_tree::<BE, DomainCode>(codes.clone()) {
+        Ok(x) => x,
+        Err(e) => {
+            problems.push(format!("Failed to build decoding tree: {:?}", e));
+            return problems;
+        },
+    };
+    let write_tree = match compile_write_tree::<BE, DomainCode>(codes.clone()) {
+        Ok(x) => x,
+        Err(e) => {
+            problems.push(format!("Failed to build encoding tree: {:?}", e));
+            return problems;
+        },
+    };
+    for &(code, _) in &codes {
+        let mut buffer = Vec::new();
+        {
+            let mut writer = BitWriter::<BE>::new(&mut buffer);
+            writer.write_huffman(&write_tree, code).unwrap();
+            writer.byte_align().unwrap();
+        }
+        let mut cursor = Cursor::new(&buffer);
+        let decoded = BitReader::<BE>::new(&mut cursor).read_huffman(&read_tree);
+        if decoded.ok() != Some(code) {
+            problems.push(format!("Symbol {:?} doesn't survive encoding and decoding", code));
+        }
+    }
+    problems
+}
@@ -1,6 +1,6 @@
 extern crate env_logger;
 #[macro_use] extern crate log;
-extern crate mioco;
+#[macro_use] extern crate mioco;
 extern crate libc;
 extern crate byteorder;
 #[macro_use] extern crate failure;
@@ -10,9 +10,17 @@ extern crate bitstream_io;
 extern crate trust_dns_proto;
 #[macro_use] extern crate structopt;
 extern crate privdrop;
+extern crate base64;
+extern crate rand;
+#[macro_use] extern crate serde_json;
+#[macro_use] extern crate serde_derive;
+extern crate serde;
+extern crate toml;
+extern crate signal_hook;
 
-use std::net::{SocketAddr, SocketAddrV6, SocketAddrV4};
+use std::net::{SocketAddr, SocketAddrV6};
 use std::os::unix::io::{AsRawFd};
+use std::sync::Arc;
 use mioco::tcp::{TcpListener};
 use libc::{SOL_IP, SOL_SOCKET, SO_REUSEADDR};
 use structopt::StructOpt;
@@ -23,58 +31,254 @@ mod huffman;
 mod socks5;
 mod utils;
 mod dns;
+mod edns;
+mod acl;
 mod connection;
+mod socks4;
+mod upstream;
+mod http_connect;
+mod happy_eyeballs;
+mod timeout;
+mod health;
+mod pool;
+mod relay;
+mod access_log;
+mod metrics;
+mod admin;
+mod shutdown;
+mod config;
+mod check;
 
 use utils::{setsockopt_bool, IP_TRANSPARENT, Result};
 use dns::serve_dns;
-use connection::handle_connection;
-use socks5::Socks5Target;
+use acl::IpPrefix;
+use connection::serve_connections;
+use socks5::ServerCredentials;
+use upstream::{Alias, Protocol, Rule, ServerHeader, SharedUpstreams};
+use pool::Pool;
+use config::Settings;
 
-#[derive(Debug, StructOpt)]
+#[derive(Debug, Default, StructOpt)]
 struct Opt {
-    /// IP and port of connection handler
-    #[structopt(short = "b", default_value = "[::1]:44555")]
-    bind: SocketAddrV6,
-    /// IP and port of DNS server
-    #[structopt(short = "d", long = "bind-dns", default_value = "[::]:53")]
-    bind_dns: SocketAddr,
-    #[structopt(short = "u", default_value = "nobody")]
-    user: String,
-    #[structopt(short = "g", default_value = "nogroup")]
-    group: String,
-    #[structopt(long = "default-server-host", default_value = "socks.rg")]
-    default_server_host: String,
-    #[structopt(long = "default-server-port", default_value = "1080")]
-    default_server_port: u16,
+    /// TOML configuration file, command line options take precedence over it.
+    /// Repeatable options are added to those in the file. Reloaded on SIGHUP, so it must be readable by the user given with -u.
+    #[structopt(short = "c", long = "config")]
+    config: Option<String>,
+    /// IP and port of connection handler [default: [::1]:44555]
+    #[structopt(short = "b")]
+    bind: Option<SocketAddrV6>,
+    /// IP and port of DNS server (repeatable), served over both UDP and TCP [default: [::]:53]
+    #[structopt(short = "d", long = "bind-dns", raw(number_of_values = "1"))]
+    bind_dns: Vec<SocketAddr>,
+    /// User to run as after binding sockets [default: nobody]
+    #[structopt(short = "u")]
+    user: Option<String>,
+    /// Group to run as after binding sockets [default: nogroup]
+    #[structopt(short = "g")]
+    group: Option<String>,
+    /// Host of default server [default: socks.rg]
+    #[structopt(long = "default-server-host")]
+    default_server_host: Option<String>,
+    /// Port of default server [default: 1080]
+    #[structopt(long = "default-server-port")]
+    default_server_port: Option<u16>,
+    /// Protocol of default server, socks5, socks4 or http [default: socks5]
+    #[structopt(long = "default-server-protocol")]
+    default_server_protocol: Option<Protocol>,
+    /// Alias or pool of default server, overrides other default server options. Use `direct` to connect without proxy.
+    /// When repeated, the first healthy one is used.
+    #[structopt(long = "default-server", raw(number_of_values = "1"))]
+    default_server: Vec<String>,
+    /// Seconds between health checks of default servers and pool members [default: 30]
+    #[structopt(long = "health-check-interval")]
+    health_check_interval: Option<u64>,
+    /// Target connected through default servers to check their health, in format of host:port.
+    /// Health checking is disabled if not specified.
+    #[structopt(long = "health-check-target")]
+    health_check_target: Option<String>,
+    /// Consecutive successful health checks before a server is considered up again [default: 3]
+    #[structopt(long = "health-check-rise")]
+    health_check_rise: Option<u32>,
+    /// Consecutive failed health checks before a server is considered down [default: 2]
+    #[structopt(long = "health-check-fall")]
+    health_check_fall: Option<u32>,
+    /// Named server that can be referenced with `a---s.<name>` in DNS queries,
+    /// in format of name=protocol://[username[:password]@]host:port (repeatable).
+    /// Comma separated list of servers makes a chain, each one is connected through the previous one.
+    #[structopt(long = "alias", raw(number_of_values = "1"))]
+    alias: Vec<Alias>,
+    /// Load balanced group of aliases, usable wherever an alias is, in format of
+    /// name=strategy:alias[*weight],alias[*weight]... (repeatable).
+    /// Strategy is round-robin, least-connections, weighted-random, hash-client or hash-target.
+    #[structopt(long = "pool", raw(number_of_values = "1"))]
+    pool: Vec<Pool>,
+    /// Route connections without explicit server to an alias by target, in format of domain=alias or prefix=alias (repeatable).
+    /// Domains also match their subdomains, first matching rule wins.
+    #[structopt(long = "route", raw(number_of_values = "1"))]
+    route: Vec<Rule>,
+    /// Credentials of SOCKS server, in format of host:port=username[:password] or alias=username[:password] (repeatable)
+    #[structopt(long = "credentials", raw(number_of_values = "1"))]
+    credentials: Vec<ServerCredentials>,
+    /// SOCKS server that doesn't support pipelined handshake, in format of host:port or alias (repeatable)
+    #[structopt(long = "strict-handshake", raw(number_of_values = "1"))]
+    strict_handshake: Vec<String>,
+    /// Extra header of HTTP CONNECT requests, in format of host:port=Name: value or alias=Name: value (repeatable)
+    #[structopt(long = "proxy-header", raw(number_of_values = "1"))]
+    proxy_header: Vec<ServerHeader>,
+    /// Seconds to wait for TCP connection to upstream, 0 to wait until the OS gives up [default: 10]
+    #[structopt(long = "connect-timeout")]
+    connect_timeout: Option<u64>,
+    /// Seconds to wait for handshakes with upstream to complete, 0 to wait forever [default: 10]
+    #[structopt(long = "handshake-timeout")]
+    handshake_timeout: Option<u64>,
+    /// Seconds to wait for the first byte from target after the client has sent data, 0 to wait forever [default: 0]
+    #[structopt(long = "first-byte-timeout")]
+    first_byte_timeout: Option<u64>,
+    /// Seconds without data in either direction before a connection is closed, 0 to wait forever [default: 0]
+    #[structopt(long = "idle-timeout")]
+    idle_timeout: Option<u64>,
+    /// Seconds a connection can stay open after one side has closed, 0 to wait forever [default: 0]
+    #[structopt(long = "half-closed-timeout")]
+    half_closed_timeout: Option<u64>,
+    /// Maximum seconds a connection can stay open, 0 for no limit [default: 0]
+    #[structopt(long = "max-lifetime")]
+    max_lifetime: Option<u64>,
+    /// Seconds to wait for connections to finish on SIGTERM or SIGINT before closing them [default: 30]
+    #[structopt(long = "drain-timeout")]
+    drain_timeout: Option<u64>,
+    /// Maximum number of connections decoding their target and connecting to upstreams at the same time [default: 256]
+    #[structopt(long = "max-handshakes")]
+    max_handshakes: Option<usize>,
+    /// Maximum number of accepted connections waiting for a handshake slot, further connections are reset [default: 1024]
+    #[structopt(long = "accept-queue")]
+    accept_queue: Option<usize>,
+    /// TTL of DNS answers encoding domain names [default: 86400]
+    #[structopt(long = "ttl-stateless")]
+    ttl_stateless: Option<u32>,
+    /// TTL of DNS answers encoding raw IPv4 addresses [default: 86400]
+    #[structopt(long = "ttl-ipv4")]
+    ttl_ipv4: Option<u32>,
+    /// TTL of DNS answers encoding pre-resolved domains [default: 15]
+    #[structopt(long = "ttl-resolved")]
+    ttl_resolved: Option<u32>,
+    /// TTL of DNS answers referencing server aliases [default: 3600]
+    #[structopt(long = "ttl-alias")]
+    ttl_alias: Option<u32>,
+    /// TTL of negative DNS answers [default: 15]
+    #[structopt(long = "ttl-negative")]
+    ttl_negative: Option<u32>,
+    /// Answer HTTPS queries with `ipv6hint` of the encoded address
+    #[structopt(long = "synthesize-https")]
+    synthesize_https: bool,
+    /// Only answer DNS queries from these prefixes (repeatable)
+    #[structopt(long = "dns-allow", raw(number_of_values = "1"))]
+    dns_allow: Vec<IpPrefix>,
+    /// Never answer DNS queries from these prefixes (repeatable)
+    #[structopt(long = "dns-deny", raw(number_of_values = "1"))]
+    dns_deny: Vec<IpPrefix>,
+    /// DNS queries per second allowed per client network, 0 to disable rate limiting [default: 0]
+    #[structopt(long = "dns-rate-limit")]
+    dns_rate_limit: Option<u32>,
+    /// Burst size of DNS rate limiting [default: 20]
+    #[structopt(long = "dns-rate-burst")]
+    dns_rate_burst: Option<u32>,
+    /// Send a truncated response to every n-th rate limited query, 0 to drop them all [default: 2]
+    #[structopt(long = "dns-rate-slip")]
+    dns_rate_slip: Option<u32>,
+    /// Write a JSON record for each connection and DNS query to this file, or `-` for stdout.
+    /// The file is reopened on SIGHUP.
+    #[structopt(long = "access-log")]
+    access_log: Option<String>,
+    /// Serve Prometheus metrics over HTTP at /metrics on this address
+    #[structopt(long = "metrics-bind")]
+    metrics_bind: Option<SocketAddr>,
+    /// Unix socket accepting admin commands, see `guruguru admin help`
+    #[structopt(long = "admin-socket")]
+    admin_socket: Option<String>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn run() -> Result<()> {
-    let opt = Opt::from_args();
-    let listener = TcpListener::bind(&opt.bind.into())?;
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Send a command to a running instance through its admin socket
+    #[structopt(name = "admin")]
+    Admin {
+        /// Path of the admin socket
+        #[structopt(long = "socket", default_value = "/run/guruguru.sock")]
+        socket: String,
+        /// Command and its arguments, `help` lists available commands
+        #[structopt(name = "COMMAND")]
+        args: Vec<String>,
+    },
+    /// Check a config file together with other command line options without starting,
+    /// printing problems with their line numbers and exiting non-zero if any is found
+    #[structopt(name = "check-config")]
+    CheckConfig {
+        /// Path of the config file
+        #[structopt(name = "PATH")]
+        path: String,
+    },
+}
+
+fn run(opt: Opt) -> Result<()> {
+    let settings = Settings::load(&opt)?;
+    let listener = TcpListener::bind(&settings.bind.into())?;
     let local_addr = listener.local_addr()?;
     setsockopt_bool(listener.as_raw_fd(), SOL_SOCKET, SO_REUSEADDR, true)?;
     setsockopt_bool(listener.as_raw_fd(), SOL_IP, IP_TRANSPARENT, true)?;
     info!("Listening on [{}]", local_addr);
-    serve_dns(opt.bind_dns)?;
+    let upstreams = settings.upstreams()?;
+    if let Some(ref target) = settings.access_log {
+        access_log::init(target)?;
+    }
+    if let Some(ref addr) = settings.metrics_bind {
+        metrics::serve_metrics(addr)?;
+    }
+    let dns = serve_dns(&settings.bind_dns, settings.dns_config(&upstreams))?;
+    let health_checked = upstreams.health_checked();
+    let upstreams = Arc::new(SharedUpstreams::new(upstreams));
+    if let Some(ref path) = settings.admin_socket {
+        admin::serve_admin(path, upstreams.clone())?;
+    }
     PrivDrop::default()
-        .user(&opt.user).context(format_err!("Can't find user: {}", opt.user))?
-        .group(&opt.group).context(format_err!("Can't find group: {}", opt.group))?
+        .user(&settings.user).context(format_err!("Can't find user: {}", settings.user))?
+        .group(&settings.group).context(format_err!("Can't find group: {}", settings.group))?
         .apply().context("Failed to drop privilege")?;
-    let default_server = match opt.default_server_host.parse() {
-        Ok(x) => Socks5Target::IP4(SocketAddrV4::new(x, opt.default_server_port)),
-        Err(_) => Socks5Target::Domain(opt.default_server_host.clone(), opt.default_server_port),
-    };
-    loop {
-        let stream = listener.accept()?;
-        if let Err(e) = handle_connection(stream, &default_server) {
-            warn!("{}", e);
-        }
+    if let Some(ref check) = settings.health_check {
+        health::spawn_checkers(health_checked, check.clone(), settings.timeouts.clone());
+    }
+    shutdown::install()?;
+    let limits = settings.limits.clone();
+    let drain_timeout_ms = settings.drain_timeout_ms;
+    if opt.config.is_some() {
+        config::spawn_reloader(opt, settings, upstreams.clone(), dns)?;
     }
+    serve_connections(listener, upstreams, limits)?;
+    shutdown::drain(drain_timeout_ms);
+    // DNS and other background coroutines never finish on their own
+    std::process::exit(0);
 }
 
 fn main() -> Result<()> {
     env_logger::init();
     let mut config = mioco::Config::new();
     config.set_catch_panics(false);
-    mioco::Mioco::new_configured(config).start(run).unwrap()
+    let opt = Opt::from_args();
+    match opt.command {
+        Some(Command::Admin { ref socket, ref args }) => return admin::run_client(socket, args),
+        Some(Command::CheckConfig { ref path }) => {
+            let problems = check::check_config(path, &opt);
+            for x in &problems {
+                eprintln!("{}", x);
+            }
+            if !problems.is_empty() {
+                std::process::exit(1);
+            }
+            println!("{}: OK", path);
+            return Ok(());
+        },
+        None => {},
+    }
+    mioco::Mioco::new_configured(config).start(move || run(opt)).unwrap()
 }
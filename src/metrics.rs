@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use mioco::tcp::{TcpListener, TcpStream};
+use mioco::timer::Timer;
+use mioco;
+use failure::ResultExt;
+
+use acl::DNS_COUNTERS;
+use connection::CONNECTION_COUNTERS;
+use timeout::with_timeout;
+use utils::{Result, ACCEPT_BACKOFF_MS, is_fd_exhausted};
+
+/// Upper bounds of handshake latency buckets in seconds
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const MAX_REQUEST_SIZE: usize = 8192;
+/// Milliseconds a client can take to send its request or read the response
+const CLIENT_TIMEOUT_MS: u64 = 10000;
+
+struct Histogram {
+    /// Observations in each of `LATENCY_BUCKETS`, not cumulative
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram { counts: vec![0; LATENCY_BUCKETS.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&x| value <= x) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Metrics {
+    /// Keyed by query type and result
+    dns_queries: HashMap<(String, String), u64>,
+    dns_encode_failures: HashMap<String, u64>,
+    /// Keyed by error class
+    connections_failed: HashMap<String, u64>,
+    /// Keyed by upstream
+    handshake_latency: HashMap<String, Histogram>,
+    /// Keyed by upstream and direction, counted when connections close
+    bytes: HashMap<(String, &'static str), u64>,
+}
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+}
+
+pub fn dns_query(query_type: &str, result: &str) {
+    *METRICS.lock().unwrap().dns_queries.entry((query_type.into(), result.into())).or_insert(0) += 1;
+}
+
+pub fn dns_encode_failure(reason: &str) {
+    *METRICS.lock().unwrap().dns_encode_failures.entry(reason.into()).or_insert(0) += 1;
+}
+
+pub fn connection_failed(class: &str) {
+    *METRICS.lock().unwrap().connections_failed.entry(class.into()).or_insert(0) += 1;
+}
+
+pub fn handshake_completed(upstream: &str, seconds: f64) {
+    METRICS.lock().unwrap().handshake_latency.entry(upstream.into()).or_insert_with(Histogram::new).observe(seconds);
+}
+
+pub fn bytes_transferred(upstream: &str, up: u64, down: u64) {
+    let mut metrics = METRICS.lock().unwrap();
+    *metrics.bytes.entry((upstream.into(), "up")).or_insert(0) += up;
+    *metrics.bytes.entry((upstream.into(), "down")).or_insert(0) += down;
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', r"\\").replace('"', "\\\"").replace('\n', r"\n")
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Renders all metrics in Prometheus text format
+pub fn render() -> String {
+    let mut out = String::new();
+    {
+        let metrics = METRICS.lock().unwrap();
+        header(&mut out, "guruguru_dns_queries_total", "counter", "DNS queries by type and result");
+        for (&(ref query_type, ref result), count) in &metrics.dns_queries {
+            writeln!(out, "guruguru_dns_queries_total{{type=\"{}\",result=\"{}\"}} {}", escape(query_type), escape(result), count).unwrap();
+        }
+        header(&mut out, "guruguru_dns_encode_failures_total", "counter", "Names that couldn't be encoded into an address, by reason");
+        for (reason, count) in &metrics.dns_encode_failures {
+            writeln!(out, "guruguru_dns_encode_failures_total{{reason=\"{}\"}} {}", escape(reason), count).unwrap();
+        }
+        header(&mut out, "guruguru_connections_failed_total", "counter", "Connections that failed before relaying, by error class");
+        for (class, count) in &metrics.connections_failed {
+            writeln!(out, "guruguru_connections_failed_total{{error=\"{}\"}} {}", escape(class), count).unwrap();
+        }
+        header(&mut out, "guruguru_handshake_duration_seconds", "histogram", "Time to connect to targets through upstreams");
+        for (upstream, histogram) in &metrics.handshake_latency {
+            let upstream = escape(upstream);
+            let mut cumulative = 0;
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(&histogram.counts) {
+                cumulative += count;
+                writeln!(out, "guruguru_handshake_duration_seconds_bucket{{upstream=\"{}\",le=\"{}\"}} {}", upstream, bound, cumulative).unwrap();
+            }
+            writeln!(out, "guruguru_handshake_duration_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}", upstream, histogram.count).unwrap();
+            writeln!(out, "guruguru_handshake_duration_seconds_sum{{upstream=\"{}\"}} {}", upstream, histogram.sum).unwrap();
+            writeln!(out, "guruguru_handshake_duration_seconds_count{{upstream=\"{}\"}} {}", upstream, histogram.count).unwrap();
+        }
+        header(&mut out, "guruguru_transferred_bytes_total", "counter", "Bytes relayed through upstreams, counted when connections close");
+        for (&(ref upstream, direction), count) in &metrics.bytes {
+            writeln!(out, "guruguru_transferred_bytes_total{{upstream=\"{}\",direction=\"{}\"}} {}", escape(upstream), direction, count).unwrap();
+        }
+    }
+    let counters = [
+        ("guruguru_connections_accepted_total", "counter", "Accepted connections", &CONNECTION_COUNTERS.accepted),
+        ("guruguru_connections_overflowed_total", "counter", "Connections reset because the accept queue was full", &CONNECTION_COUNTERS.overflowed),
+        ("guruguru_accept_queue", "gauge", "Accepted connections waiting for a handshake slot", &CONNECTION_COUNTERS.queued),
+        ("guruguru_handshakes_active", "gauge", "Connections connecting to upstreams", &CONNECTION_COUNTERS.handshaking),
+        ("guruguru_relays_active", "gauge", "Connections relaying data", &CONNECTION_COUNTERS.relaying),
+        ("guruguru_dns_denied_total", "counter", "DNS queries denied by ACL", &DNS_COUNTERS.denied),
+        ("guruguru_dns_rate_limited_total", "counter", "DNS queries dropped by rate limiting", &DNS_COUNTERS.limited),
+        ("guruguru_dns_slipped_total", "counter", "DNS queries answered with truncated responses by rate limiting", &DNS_COUNTERS.slipped),
+    ];
+    for &(name, kind, help, value) in &counters {
+        header(&mut out, name, kind, help);
+        writeln!(out, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+    }
+    out
+}
+
+fn read_request(stream: &mut TcpStream) -> Result<String> {
+    let mut request = Vec::new();
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") && !request.windows(2).any(|x| x == b"\n\n") {
+        match stream.read(&mut buffer)? {
+            0 => bail!("Unexpected EOF"),
+            x => request.extend(&buffer[..x]),
+        };
+        if request.len() > MAX_REQUEST_SIZE {
+            bail!("Request is too large");
+        }
+    }
+    Ok(String::from_utf8_lossy(&request).lines().next().unwrap_or("").to_string())
+}
+
+fn serve_client(mut stream: TcpStream) -> Result<()> {
+    let request_line = with_timeout(&mut stream, CLIENT_TIMEOUT_MS, "Reading metrics request", read_request)?;
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), _) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    with_timeout(&mut stream, CLIENT_TIMEOUT_MS, "Sending metrics", |stream| {
+        write!(
+            stream,
+            "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status, body.len(), body,
+        )?;
+        Ok(())
+    })
+}
+
+/// Binds `addr` and serves `/metrics` over HTTP in background
+pub fn serve_metrics(addr: &SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).context(format_err!("Failed to bind metrics server to {}", addr))?;
+    info!("Serving metrics on [{}]", addr);
+    mioco::spawn(move || {
+        loop {
+            let stream = match listener.accept() {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("Failed to accept metrics connection: {}", e);
+                    if is_fd_exhausted(&e) {
+                        let mut timer = Timer::new();
+                        timer.set_timeout(ACCEPT_BACKOFF_MS as i64);
+                        select!(r:timer => {});
+                    }
+                    continue
+                },
+            };
+            mioco::spawn(move || {
+                if let Err(e) = serve_client(stream) {
+                    debug!("Metrics connection closed: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{self, Display};
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use failure::Error;
+use rand::{self, Rng};
+
+use socks5::Socks5Target;
+use upstream::Upstream;
+use health;
+use utils::Result;
+
+lazy_static! {
+    /// Relayed connections per upstream, keyed by display string of upstreams
+    static ref ACTIVE: Mutex<HashMap<String, usize>> = Mutex::new(HashMap::new());
+}
+
+/// Counts an active connection through an upstream until dropped
+pub struct ActiveConnection(String);
+
+impl ActiveConnection {
+    pub fn new(upstream: &Upstream) -> ActiveConnection {
+        let name = upstream.to_string();
+        *ACTIVE.lock().unwrap().entry(name.clone()).or_insert(0) += 1;
+        ActiveConnection(name)
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut active = ACTIVE.lock().unwrap();
+        if let Some(x) = active.get_mut(&self.0) {
+            *x = x.saturating_sub(1);
+        }
+    }
+}
+
+pub fn active_connections(upstream: &Upstream) -> usize {
+    ACTIVE.lock().unwrap().get(&upstream.to_string()).cloned().unwrap_or(0)
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Strategy {
+    RoundRobin,
+    LeastConnections,
+    WeightedRandom,
+    /// Consistent hashing by client IP
+    HashClient,
+    /// Consistent hashing by target domain or IP
+    HashTarget,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Strategy> {
+        match &s.to_lowercase()[..] {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "weighted-random" => Ok(Strategy::WeightedRandom),
+            "hash-client" => Ok(Strategy::HashClient),
+            "hash-target" => Ok(Strategy::HashTarget),
+            _ => bail!("Unknown load balancing strategy: {}", s),
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Strategy::RoundRobin => "round-robin",
+            Strategy::LeastConnections => "least-connections",
+            Strategy::WeightedRandom => "weighted-random",
+            Strategy::HashClient => "hash-client",
+            Strategy::HashTarget => "hash-target",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolMember {
+    pub alias: String,
+    pub weight: u32,
+}
+
+/// Load balanced group of aliases, parsed from `name=strategy:alias[*weight],alias[*weight]...`
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    pub strategy: Strategy,
+    pub members: Vec<PoolMember>,
+    next: AtomicUsize,
+}
+
+impl Clone for Pool {
+    fn clone(&self) -> Pool {
+        Pool {
+            name: self.name.clone(),
+            strategy: self.strategy,
+            members: self.members.clone(),
+            next: AtomicUsize::new(self.next.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl FromStr for Pool {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Pool> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap().trim().to_lowercase();
+        let definition = match parts.next() {
+            Some(x) => x,
+            None => bail!("Expected name=strategy:alias,alias..."),
+        };
+        let mut parts = definition.splitn(2, ':');
+        let strategy = parts.next().unwrap().trim().parse()?;
+        let members = match parts.next() {
+            Some(x) => x.split(',').map(|member| {
+                let mut parts = member.splitn(2, '*');
+                let alias = parts.next().unwrap().trim().to_lowercase();
+                let weight = match parts.next() {
+                    Some(x) => x.trim().parse().map_err(|_| format_err!("Invalid weight of pool member: {}", member))?,
+                    None => 1,
+                };
+                if alias.is_empty() || weight == 0 {
+                    bail!("Invalid pool member: {}", member);
+                }
+                Ok(PoolMember { alias: alias, weight: weight })
+            }).collect::<Result<Vec<_>>>()?,
+            None => bail!("Pool {} has no members", name),
+        };
+        if name.is_empty() {
+            bail!("Pool name is empty");
+        }
+        Ok(Pool { name: name, strategy: strategy, members: members, next: AtomicUsize::new(0) })
+    }
+}
+
+/// Information about a connection that strategies may select by
+pub struct Selection<'a> {
+    pub client: IpAddr,
+    pub target: &'a Socks5Target,
+}
+
+/// Weighted rendezvous hashing score, stable as long as the member exists
+fn rendezvous_score<K: Hash>(key: &K, member: &PoolMember) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    member.alias.hash(&mut hasher);
+    // Map to (0, 1)
+    let unit = (hasher.finish() as f64 + 1.0) / (u64::max_value() as f64 + 2.0);
+    -(member.weight as f64) / unit.ln()
+}
+
+impl Pool {
+    /// Picks a member, skipping unhealthy ones unless all of them are down
+    pub fn select(&self, upstreams: &HashMap<String, Upstream>, selection: &Selection) -> Upstream {
+        let all: Vec<_> = self.members.iter().map(|x| (x, &upstreams[&x.alias])).collect();
+        let healthy: Vec<_> = all.iter().cloned().filter(|x| health::is_up(x.1)).collect();
+        let candidates = if healthy.is_empty() { &all } else { &healthy };
+        let total_weight: u64 = candidates.iter().map(|x| x.0.weight as u64).sum();
+        let pick_by_weight = |mut n: u64| {
+            for x in candidates {
+                if n < x.0.weight as u64 {
+                    return x.1;
+                }
+                n -= x.0.weight as u64;
+            }
+            candidates[0].1
+        };
+        let chosen = match self.strategy {
+            Strategy::RoundRobin => pick_by_weight(self.next.fetch_add(1, Ordering::Relaxed) as u64 % total_weight),
+            Strategy::WeightedRandom => pick_by_weight(rand::thread_rng().gen_range(0, total_weight)),
+            Strategy::LeastConnections => candidates.iter().min_by(|a, b| {
+                let load_a = active_connections(a.1) as f64 / a.0.weight as f64;
+                let load_b = active_connections(b.1) as f64 / b.0.weight as f64;
+                load_a.partial_cmp(&load_b).unwrap()
+            }).unwrap().1,
+            Strategy::HashClient => candidates.iter().max_by(|a, b| {
+                rendezvous_score(&selection.client, a.0).partial_cmp(&rendezvous_score(&selection.client, b.0)).unwrap()
+            }).unwrap().1,
+            Strategy::HashTarget => {
+                let key = match *selection.target {
+                    Socks5Target::Domain(ref domain, _) => domain.to_lowercase(),
+                    Socks5Target::IP4(ref x) => x.ip().to_string(),
+                    Socks5Target::IP6(ref x) => x.ip().to_string(),
+                };
+                candidates.iter().max_by(|a, b| {
+                    rendezvous_score(&key, a.0).partial_cmp(&rendezvous_score(&key, b.0)).unwrap()
+                }).unwrap().1
+            },
+        };
+        chosen.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(s: &str) -> Pool {
+        s.parse().unwrap()
+    }
+
+    /// Upstreams of the given aliases, with servers in `10.<net>.0.0/24` so tests don't share connection counts
+    fn upstreams(aliases: &[&str], net: u8) -> HashMap<String, Upstream> {
+        aliases.iter().enumerate()
+            .map(|(i, x)| (x.to_string(), format!("socks5://10.{}.0.{}:1080", net, i + 1).parse().unwrap()))
+            .collect()
+    }
+
+    fn select(pool: &Pool, upstreams: &HashMap<String, Upstream>, client: &str, target: &Socks5Target) -> String {
+        let selection = Selection { client: client.parse().unwrap(), target: target };
+        let chosen = pool.select(upstreams, &selection).to_string();
+        upstreams.iter().find(|x| x.1.to_string() == chosen).unwrap().0.clone()
+    }
+
+    fn target(domain: &str) -> Socks5Target {
+        Socks5Target::Domain(domain.into(), 443)
+    }
+
+    #[test]
+    fn parse() {
+        let x = pool(" Edge = Hash-Client: a*2, B ");
+        assert_eq!(x.name, "edge");
+        assert_eq!(x.strategy, Strategy::HashClient);
+        let members: Vec<_> = x.members.iter().map(|x| (&x.alias[..], x.weight)).collect();
+        assert_eq!(members, vec![("a", 2), ("b", 1)]);
+        for s in &["edge", "edge=round-robin", "edge=random:a", "edge=round-robin:a*0", "edge=round-robin:a*x", "edge=round-robin:a,", "=round-robin:a"] {
+            assert!(s.parse::<Pool>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn consistent_hashing() {
+        let members = ["m0", "m1", "m2", "m3", "m4"];
+        let upstreams = upstreams(&members, 1);
+        let full = pool("edge=hash-client:m0,m1,m2,m3,m4");
+        let reduced = pool("edge=hash-client:m0,m1,m3,m4");
+        let target = target("example.com");
+        let clients: Vec<_> = (0..200).map(|i| format!("192.168.{}.{}", i / 10, i % 10)).collect();
+        let before: Vec<_> = clients.iter().map(|x| select(&full, &upstreams, x, &target)).collect();
+        for (client, chosen) in clients.iter().zip(&before) {
+            assert_eq!(&select(&full, &upstreams, client, &target), chosen);
+            let after = select(&reduced, &upstreams, client, &target);
+            if chosen == "m2" {
+                assert_ne!(after, "m2");
+            } else {
+                assert_eq!(&after, chosen);
+            }
+        }
+        for x in &members {
+            assert!(before.iter().any(|y| y == x), "{} is never chosen", x);
+        }
+    }
+
+    #[test]
+    fn hash_target() {
+        let upstreams = upstreams(&["a", "b", "c"], 2);
+        let x = pool("edge=hash-target:a,b,c");
+        for i in 0..50 {
+            let domain = format!("host{}.example.com", i);
+            let chosen = select(&x, &upstreams, "192.168.0.1", &target(&domain));
+            assert_eq!(select(&x, &upstreams, "192.168.0.2", &target(&domain.to_uppercase())), chosen);
+        }
+    }
+
+    #[test]
+    fn zero_weight() {
+        let upstreams = upstreams(&["a", "b", "c"], 3);
+        let target = target("example.com");
+        for strategy in &["weighted-random", "round-robin", "hash-client"] {
+            let mut x = pool(&format!("edge={}:a,b*5,c", strategy));
+            x.members[1].weight = 0;
+            for i in 0..300 {
+                let client = format!("192.168.{}.{}", i / 100, i % 100);
+                assert_ne!(select(&x, &upstreams, &client, &target), "b", "{}", strategy);
+            }
+        }
+    }
+
+    #[test]
+    fn least_connections() {
+        let upstreams = upstreams(&["a", "b", "c"], 4);
+        let target = target("example.com");
+        let x = pool("edge=least-connections:a,b,c");
+        let _a = ActiveConnection::new(&upstreams["a"]);
+        let _c = (ActiveConnection::new(&upstreams["c"]), ActiveConnection::new(&upstreams["c"]));
+        assert_eq!(select(&x, &upstreams, "192.168.0.1", &target), "b");
+        let b = ActiveConnection::new(&upstreams["b"]);
+        let _b = ActiveConnection::new(&upstreams["b"]);
+        assert_eq!(select(&x, &upstreams, "192.168.0.1", &target), "a");
+        drop(b);
+        assert_eq!(active_connections(&upstreams["b"]), 1);
+        // Load is relative to weight
+        let x = pool("edge=least-connections:a,b,c*4");
+        assert_eq!(select(&x, &upstreams, "192.168.0.1", &target), "c");
+    }
+}
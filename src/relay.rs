@@ -0,0 +1,244 @@
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use mioco::tcp::TcpStream;
+
+use utils::Result;
+
+// splice and pipe2 only exist on Linux, elsewhere relays always copy through userspace
+#[cfg(target_os = "linux")]
+use std::io::{Error as IoError, ErrorKind};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(target_os = "linux")]
+use std::ptr;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT};
+#[cfg(target_os = "linux")]
+use libc::{self, c_int, loff_t, EINVAL, ENOSYS, O_CLOEXEC, O_NONBLOCK, SPLICE_F_MOVE, SPLICE_F_NONBLOCK};
+
+/// Bytes moved by one `splice` call, also the size requested for the pipe
+#[cfg(target_os = "linux")]
+const SPLICE_CHUNK: usize = 65536;
+#[cfg(target_os = "linux")]
+const F_SETPIPE_SZ: c_int = 1031;
+
+/// Set once splice turns out to be unavailable, so later relays go straight to buffered copy
+#[cfg(target_os = "linux")]
+static SPLICE_UNSUPPORTED: AtomicBool = ATOMIC_BOOL_INIT;
+
+#[cfg(target_os = "linux")]
+#[derive(Fail, Debug)]
+#[fail(display = "splice is not supported")]
+pub struct SpliceUnsupported;
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Direction {
+    /// From client to upstream
+    Up,
+    /// From upstream to client
+    Down,
+}
+
+impl Direction {
+    /// Side that closes this direction
+    pub fn sender(&self) -> &'static str {
+        match *self {
+            Direction::Up => "client",
+            Direction::Down => "server",
+        }
+    }
+}
+
+/// Tracks data flowing through a relay in both directions
+pub struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`
+    last: AtomicUsize,
+    bytes_up: AtomicUsize,
+    bytes_down: AtomicUsize,
+    /// 0 while both directions are open, otherwise 1 for up and 2 for down
+    first_finished: AtomicUsize,
+}
+
+impl Activity {
+    pub fn new() -> Activity {
+        Activity {
+            start: Instant::now(),
+            last: AtomicUsize::new(0),
+            bytes_up: AtomicUsize::new(0),
+            bytes_down: AtomicUsize::new(0),
+            first_finished: AtomicUsize::new(0),
+        }
+    }
+
+    /// Milliseconds since the relay started
+    pub fn elapsed_ms(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000
+    }
+
+    /// Milliseconds since the relay started when data last flowed
+    pub fn last_ms(&self) -> u64 {
+        self.last.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn transferred(&self, direction: Direction, num_bytes: usize) {
+        match direction {
+            Direction::Up => &self.bytes_up,
+            Direction::Down => &self.bytes_down,
+        }.fetch_add(num_bytes, Ordering::Relaxed);
+        self.last.store(self.elapsed_ms() as usize, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::Up => &self.bytes_up,
+            Direction::Down => &self.bytes_down,
+        }.load(Ordering::Relaxed) as u64
+    }
+
+    fn finished(&self, direction: Direction) {
+        let code = match direction {
+            Direction::Up => 1,
+            Direction::Down => 2,
+        };
+        self.first_finished.compare_exchange(0, code, Ordering::Relaxed, Ordering::Relaxed).is_ok();
+    }
+
+    /// Direction that stopped first, if any
+    pub fn first_finished(&self) -> Option<Direction> {
+        match self.first_finished.load(Ordering::Relaxed) {
+            1 => Some(Direction::Up),
+            2 => Some(Direction::Down),
+            _ => None,
+        }
+    }
+}
+
+/// Pipe used as the kernel buffer between two sockets
+#[cfg(target_os = "linux")]
+struct Pipe {
+    read: RawFd,
+    write: RawFd,
+}
+
+#[cfg(target_os = "linux")]
+impl Pipe {
+    fn new() -> Result<Pipe> {
+        let mut fds = [0 as c_int; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), O_NONBLOCK | O_CLOEXEC) } != 0 {
+            return Err(IoError::last_os_error().into());
+        }
+        // Only a hint, the default size works too
+        unsafe { libc::fcntl(fds[1], F_SETPIPE_SZ, SPLICE_CHUNK as c_int) };
+        Ok(Pipe { read: fds[0], write: fds[1] })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.read);
+            libc::close(self.write);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice(from: RawFd, to: RawFd, len: usize) -> ::std::io::Result<usize> {
+    match unsafe {
+        libc::splice(from, ptr::null_mut::<loff_t>(), to, ptr::null_mut::<loff_t>(), len, SPLICE_F_MOVE | SPLICE_F_NONBLOCK)
+    } {
+        -1 => Err(IoError::last_os_error()),
+        x => Ok(x as usize),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_unsupported(e: &IoError) -> bool {
+    match e.raw_os_error() {
+        Some(EINVAL) | Some(ENOSYS) => true,
+        _ => false,
+    }
+}
+
+/// Moves data from `rx` to `tx` through a pipe without copying it to userspace.
+/// Fails with `SpliceUnsupported` before anything is transferred if the kernel can't splice these sockets.
+#[cfg(target_os = "linux")]
+pub fn splice_forever(rx: &mut TcpStream, tx: &mut TcpStream, activity: &Activity, direction: Direction) -> Result<()> {
+    let pipe = Pipe::new()?;
+    let mut transferred = false;
+    loop {
+        let mut pending = match splice(rx.as_raw_fd(), pipe.write, SPLICE_CHUNK) {
+            Ok(0) => return Ok(()),
+            Ok(x) => x,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                select!(r:rx => {});
+                continue;
+            },
+            Err(ref e) if !transferred && is_unsupported(e) => return Err(SpliceUnsupported)?,
+            Err(e) => return Err(e.into()),
+        };
+        transferred = true;
+        while pending > 0 {
+            match splice(pipe.read, tx.as_raw_fd(), pending) {
+                Ok(x) => {
+                    pending -= x;
+                    activity.transferred(direction, x);
+                },
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    select!(w:tx => {});
+                },
+                Err(e) => return Err(e.into()),
+            };
+        }
+    }
+}
+
+/// Copies data from `rx` to `tx` through a userspace buffer
+pub fn copy_forever(rx: &mut TcpStream, tx: &mut TcpStream, activity: &Activity, direction: Direction) -> Result<()> {
+    let mut buffer = [0u8; 16384];
+    loop {
+        match rx.read(&mut buffer)? {
+            0 => return Ok(()),
+            num_bytes => {
+                tx.write_all(&buffer[..num_bytes])?;
+                activity.transferred(direction, num_bytes);
+            },
+        };
+    }
+}
+
+/// Relays with splice, falling back to buffered copy for good once the kernel turns out not to support it
+#[cfg(target_os = "linux")]
+fn relay(rx: &mut TcpStream, tx: &mut TcpStream, activity: &Activity, direction: Direction) -> Result<()> {
+    if SPLICE_UNSUPPORTED.load(Ordering::Relaxed) {
+        return copy_forever(rx, tx, activity, direction);
+    }
+    let ret = splice_forever(rx, tx, activity, direction);
+    if ret.as_ref().err().map_or(false, |e| e.downcast_ref::<SpliceUnsupported>().is_some()) {
+        if !SPLICE_UNSUPPORTED.swap(true, Ordering::Relaxed) {
+            info!("splice is not supported, falling back to buffered copy");
+        }
+        return copy_forever(rx, tx, activity, direction);
+    }
+    ret
+}
+
+#[cfg(not(target_os = "linux"))]
+fn relay(rx: &mut TcpStream, tx: &mut TcpStream, activity: &Activity, direction: Direction) -> Result<()> {
+    copy_forever(rx, tx, activity, direction)
+}
+
+/// Relays `rx` to `tx` until `rx` is closed, then shuts down writing of `tx`.
+/// Transferred bytes are counted in `activity` for `direction`.
+pub fn pipe_forever(mut rx: TcpStream, mut tx: TcpStream, activity: &Activity, direction: Direction) -> Result<()> {
+    let ret = relay(&mut rx, &mut tx, activity, direction);
+    activity.finished(direction);
+    tx.shutdown(Shutdown::Write).is_ok();
+    rx.shutdown(Shutdown::Read).is_ok();
+    ret
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use mioco::timer::Timer;
+use signal_hook::{self, SIGINT, SIGTERM};
+
+use connection::{kill_all_connections, CONNECTION_COUNTERS};
+use utils::Result;
+
+/// How often shutdown is checked by loops that can't be woken by signals
+pub const POLL_INTERVAL_MS: u64 = 200;
+
+lazy_static! {
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+/// Requests shutdown on SIGTERM and SIGINT instead of exiting immediately
+pub fn install() -> Result<()> {
+    for &signal in &[SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, REQUESTED.clone())?;
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+fn pending() -> usize {
+    CONNECTION_COUNTERS.relaying.load(Ordering::Relaxed) + CONNECTION_COUNTERS.handshaking.load(Ordering::Relaxed)
+}
+
+/// Waits up to `timeout_ms` for connections to finish, then closes the remaining ones and logs what was cut off
+pub fn drain(timeout_ms: u64) {
+    let start = Instant::now();
+    info!("Shutting down, waiting up to {}ms for {} connections to finish", timeout_ms, pending());
+    while pending() > 0 {
+        let elapsed = start.elapsed();
+        let elapsed_ms = elapsed.as_secs() * 1000 + elapsed.subsec_nanos() as u64 / 1_000_000;
+        if elapsed_ms >= timeout_ms {
+            break;
+        }
+        let mut timer = Timer::new();
+        timer.set_timeout(POLL_INTERVAL_MS.min(timeout_ms - elapsed_ms) as i64);
+        select!(r:timer => {});
+    }
+    let handshaking = CONNECTION_COUNTERS.handshaking.load(Ordering::Relaxed);
+    let killed = kill_all_connections();
+    if killed.is_empty() && handshaking == 0 {
+        info!("All connections finished, exiting");
+        return;
+    }
+    let (bytes_up, bytes_down) = killed.iter().fold((0, 0), |(up, down), x| (up + x.bytes_up, down + x.bytes_down));
+    warn!(
+        "Drain timed out, cut off {} relaying connections ({} bytes up, {} bytes down so far) and {} handshakes",
+        killed.len(), bytes_up, bytes_down, handshaking,
+    );
+    for summary in &killed {
+        warn!("Cut off: {}", summary);
+    }
+}
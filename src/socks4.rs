@@ -0,0 +1,60 @@
+use std::io::{Read, Write};
+use mioco::tcp::TcpStream;
+use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+
+use socks5::{Socks5Target, SocksError};
+use Result;
+
+/// Performs SOCKS4 handshake over an established stream to the server, using SOCKS4a for domain targets
+pub fn socks4_handshake(stream: &mut TcpStream, target: &Socks5Target, user_id: Option<&str>) -> Result<()> {
+    /*
+    CONNECT request:
+    +----+----+----+----+----+----+----+----+----+----+....+----+
+    | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL|
+    +----+----+----+----+----+----+----+----+----+----+....+----+
+       1    1      2              4           variable       1
+
+    SOCKS4a: DSTIP is set to 0.0.0.x (x != 0), and the domain follows USERID:
+    +----+----+----+----+----+----+----+----+----+----+....+----+----+....+----+
+    | VN | CD | DSTPORT |      DSTIP        | USERID       |NULL| HOSTNAME |NULL|
+    +----+----+----+----+----+----+----+----+----+----+....+----+----+....+----+
+    */
+    let mut request = Vec::with_capacity(512);
+    request.write_all(&[4, 1])?;
+    match target {
+        &Socks5Target::IP4(x) => {
+            request.write_u16::<NetworkEndian>(x.port())?;
+            request.write_all(&x.ip().octets())?;
+        },
+        &Socks5Target::Domain(_, port) => {
+            request.write_u16::<NetworkEndian>(port)?;
+            request.write_all(&[0, 0, 0, 1])?;
+        },
+        &Socks5Target::IP6(_) => return Err(SocksError::UnsupportedTarget)?,
+    };
+    request.write_all(user_id.unwrap_or("").as_bytes())?;
+    request.write_u8(0)?;
+    if let &Socks5Target::Domain(ref domain, _) = target {
+        request.write_all(domain.as_bytes())?;
+        request.write_u8(0)?;
+    }
+    stream.write_all(&request)?;
+    /*
+    Reply:
+    +----+----+----+----+----+----+----+----+
+    | VN | CD | DSTPORT |      DSTIP        |
+    +----+----+----+----+----+----+----+----+
+       1    1      2              4
+    CD: 90 granted, 91 rejected or failed, 92/93 identd failures
+    */
+    if stream.read_u8()? != 0 {
+        return Err(SocksError::UnexpectedVersion)?;
+    }
+    let code = stream.read_u8()?;
+    let mut dummy = [0u8; 6];
+    stream.read_exact(&mut dummy)?;
+    if code != 90 {
+        return Err(SocksError::ServerError(code))?;
+    }
+    Ok(())
+}
@@ -1,9 +1,11 @@
 use std;
-use std::net::{Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
+use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 use std::io::{Read, Write};
 use std::fmt::Display;
+use std::str::FromStr;
 use mioco::tcp::TcpStream;
 use byteorder::{ReadBytesExt, WriteBytesExt, NetworkEndian};
+use failure::Error;
 
 use Result;
 
@@ -15,12 +17,57 @@ pub enum SocksError {
     UnexpectedVersion,
     #[fail(display = "Unexpected address type")]
     UnexpectedAddressType,
+    #[fail(display = "Target address type is not supported by the protocol")]
+    UnsupportedTarget,
     #[fail(display = "Authentication is not supported")]
     AuthenticationNotSupported,
+    #[fail(display = "Server didn't accept any of offered authentication methods")]
+    NoAcceptableMethods,
+    #[fail(display = "Authentication failed with status {}", _0)]
+    AuthenticationFailed(u8),
     #[fail(display = "Socks server returned error {}", _0)]
     ServerError(u8),
 }
 
+/// Username and password for RFC 1929 authentication
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+impl FromStr for Credentials {
+    type Err = Error;
+    /// Parses `username[:password]`, a missing password is sent as an empty one
+    fn from_str(s: &str) -> Result<Credentials> {
+        let mut parts = s.splitn(2, ':');
+        let username = parts.next().unwrap();
+        let password = parts.next().unwrap_or("");
+        if username.is_empty() || username.len() > 255 || password.len() > 255 {
+            bail!("Username must be 1 to 255 bytes long, password at most 255 bytes");
+        }
+        Ok(Credentials { username: username.into(), password: password.into() })
+    }
+}
+
+/// Credentials of a server, parsed from `host:port=username[:password]`
+#[derive(Debug, Clone)]
+pub struct ServerCredentials {
+    pub server: String,
+    pub credentials: Credentials,
+}
+impl FromStr for ServerCredentials {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<ServerCredentials> {
+        let mut parts = s.splitn(2, '=');
+        let server = parts.next().unwrap();
+        let credentials = match parts.next() {
+            Some(x) => x.parse()?,
+            None => bail!("Expected host:port=username[:password]"),
+        };
+        Ok(ServerCredentials { server: server.to_lowercase(), credentials: credentials })
+    }
+}
+
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Socks5Target {
     IP4(SocketAddrV4),
@@ -55,9 +102,26 @@ impl Display for Socks5Target {
     }
 }
 
-pub fn socks5_connect<T: ToSocketAddrs + Display>(server: T, target: Socks5Target) -> Result<TcpStream> {
-    info!("socks5_connect: [{}] -> {}", server, target);
-    let mut stream = TcpStream::connect(&server.to_socket_addrs()?.next().ok_or(SocksError::FailedToResolve)?)?;
+/// How requests are written during the handshake
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum HandshakeMode {
+    /// Send each request before reading the reply of previous one, saving round trips
+    Pipelined,
+    /// Wait for each reply before sending the next request, for servers that can't handle pipelining
+    Strict,
+}
+
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+enum HandshakeState {
+    Greeting,
+    MethodSelection { request_sent: bool },
+    Authentication,
+    AuthenticationReply { request_sent: bool },
+    Request,
+    Reply,
+}
+
+fn write_greeting(stream: &mut TcpStream, credentials: Option<&Credentials>) -> Result<()> {
     /*
     Handshake:
     +----+----------+----------+
@@ -66,8 +130,59 @@ pub fn socks5_connect<T: ToSocketAddrs + Display>(server: T, target: Socks5Targe
     | 1  |    1     | 1 to 255 |
     +----+----------+----------+
     X'00' NO AUTHENTICATION REQUIRED
+    X'02' USERNAME/PASSWORD
     */
-    stream.write_all(&[5, 1, 0])?;
+    if credentials.is_some() {
+        stream.write_all(&[5, 2, 0, 2])?;
+    } else {
+        stream.write_all(&[5, 1, 0])?;
+    }
+    Ok(())
+}
+
+/// Returns whether authentication is required
+fn read_method_selection(stream: &mut TcpStream, credentials: Option<&Credentials>) -> Result<bool> {
+    if stream.read_u8()? != 5 {
+        return Err(SocksError::UnexpectedVersion)?;
+    }
+    match (stream.read_u8()?, credentials) {
+        (0, _) => Ok(false),
+        (2, Some(_)) => Ok(true),
+        (0xff, _) => Err(SocksError::NoAcceptableMethods)?,
+        _ => Err(SocksError::AuthenticationNotSupported)?,
+    }
+}
+
+fn write_authentication(stream: &mut TcpStream, credentials: &Credentials) -> Result<()> {
+    /*
+    Username/Password request (RFC 1929):
+    +----+------+----------+------+----------+
+    |VER | ULEN |  UNAME   | PLEN |  PASSWD  |
+    +----+------+----------+------+----------+
+    | 1  |  1   | 1 to 255 |  1   | 1 to 255 |
+    +----+------+----------+------+----------+
+    */
+    let mut request = Vec::with_capacity(3 + credentials.username.len() + credentials.password.len());
+    request.push(1);
+    request.push(credentials.username.len() as u8);
+    request.extend(credentials.username.as_bytes());
+    request.push(credentials.password.len() as u8);
+    request.extend(credentials.password.as_bytes());
+    stream.write_all(&request)?;
+    Ok(())
+}
+
+fn read_authentication_reply(stream: &mut TcpStream) -> Result<()> {
+    if stream.read_u8()? != 1 {
+        return Err(SocksError::UnexpectedVersion)?;
+    }
+    match stream.read_u8()? {
+        0 => Ok(()),
+        status => Err(SocksError::AuthenticationFailed(status))?,
+    }
+}
+
+fn write_request(stream: &mut TcpStream, target: &Socks5Target) -> Result<()> {
     /*
     The SOCKS request is formed as follows:
 
@@ -93,32 +208,31 @@ pub fn socks5_connect<T: ToSocketAddrs + Display>(server: T, target: Socks5Targe
           o  DST.PORT desired destination port in network octet
              order
     */
-    stream.write_all(&[5, 1, 0])?;
-    match &target {
+    let mut request = Vec::with_capacity(262);
+    request.write_all(&[5, 1, 0])?;
+    match target {
         &Socks5Target::IP4(x) => {
-            stream.write_u8(1)?;
-            stream.write_all(&x.ip().octets())?;
-            stream.write_u16::<NetworkEndian>(x.port())?;
+            request.write_u8(1)?;
+            request.write_all(&x.ip().octets())?;
+            request.write_u16::<NetworkEndian>(x.port())?;
         },
         &Socks5Target::IP6(x) => {
-            stream.write_u8(4)?;
-            stream.write_all(&x.ip().octets())?;
-            stream.write_u16::<NetworkEndian>(x.port())?;
+            request.write_u8(4)?;
+            request.write_all(&x.ip().octets())?;
+            request.write_u16::<NetworkEndian>(x.port())?;
         },
         &Socks5Target::Domain(ref domain, port) => {
-            stream.write_u8(3)?;
-            stream.write_u8(domain.len() as u8)?;
-            stream.write_all(domain.as_bytes())?;
-            stream.write_u16::<NetworkEndian>(port)?;
+            request.write_u8(3)?;
+            request.write_u8(domain.len() as u8)?;
+            request.write_all(domain.as_bytes())?;
+            request.write_u16::<NetworkEndian>(port)?;
         },
     };
-    stream.set_nodelay(true)?;
-    if stream.read_u8()? != 5 {
-        return Err(SocksError::UnexpectedVersion)?;
-    }
-    if stream.read_u8()? != 0 {
-        return Err(SocksError::AuthenticationNotSupported)?;
-    }
+    stream.write_all(&request)?;
+    Ok(())
+}
+
+fn read_reply(stream: &mut TcpStream) -> Result<()> {
     if stream.read_u8()? != 5 {
         return Err(SocksError::UnexpectedVersion)?;
     }
@@ -138,29 +252,65 @@ pub fn socks5_connect<T: ToSocketAddrs + Display>(server: T, target: Socks5Targe
         },
         _ => return Err(SocksError::UnexpectedAddressType)?,
     };
-    debug!("socks5_connect: [{}] -> {} - Connection established", server, target);
-    Ok(stream)
+    Ok(())
 }
 
-pub fn pipe_forever(mut rx: TcpStream, mut tx: TcpStream) -> Result<()> {
-    let mut buffer = [0u8; 16384];
-    let mut ret = Ok(());
+/// Performs SOCKS5 handshake over an established stream to the server
+pub fn socks5_handshake(stream: &mut TcpStream, target: &Socks5Target, credentials: Option<&Credentials>, mode: HandshakeMode) -> Result<()> {
+    let pipelined = mode == HandshakeMode::Pipelined;
+    let mut state = HandshakeState::Greeting;
     loop {
-        match rx.read(&mut buffer) {
-            Ok(0) => break,
-            Ok(num_bytes) => {
-                if let Err(e) = tx.write_all(&buffer[..num_bytes]) {
-                    ret = Err(e.into());
-                    break;
+        state = match state {
+            HandshakeState::Greeting => {
+                write_greeting(stream, credentials)?;
+                // Request can only be sent early if no authentication will be needed
+                let request_sent = pipelined && credentials.is_none();
+                if request_sent {
+                    write_request(stream, target)?;
+                }
+                HandshakeState::MethodSelection { request_sent: request_sent }
+            },
+            HandshakeState::MethodSelection { request_sent } => {
+                match (read_method_selection(stream, credentials)?, request_sent) {
+                    (true, _) => HandshakeState::Authentication,
+                    (false, true) => HandshakeState::Reply,
+                    (false, false) => HandshakeState::Request,
+                }
+            },
+            HandshakeState::Authentication => {
+                write_authentication(stream, credentials.unwrap())?;
+                if pipelined {
+                    write_request(stream, target)?;
+                }
+                HandshakeState::AuthenticationReply { request_sent: pipelined }
+            },
+            HandshakeState::AuthenticationReply { request_sent } => {
+                read_authentication_reply(stream)?;
+                if request_sent {
+                    HandshakeState::Reply
+                } else {
+                    HandshakeState::Request
                 }
             },
-            Err(e) => {
-                ret = Err(e.into());
-                break;
-            }
+            HandshakeState::Request => {
+                write_request(stream, target)?;
+                HandshakeState::Reply
+            },
+            HandshakeState::Reply => {
+                read_reply(stream)?;
+                return Ok(());
+            },
         };
     }
-    tx.shutdown(Shutdown::Write).is_ok();
-    rx.shutdown(Shutdown::Read).is_ok();
-    ret
+}
+
+/// Whether the error may be caused by a server that can't handle pipelined requests
+pub fn is_pipelining_failure(e: &Error) -> bool {
+    match e.downcast_ref::<SocksError>() {
+        Some(&SocksError::ServerError(_)) |
+        Some(&SocksError::AuthenticationFailed(_)) |
+        Some(&SocksError::NoAcceptableMethods) |
+        Some(&SocksError::AuthenticationNotSupported) => false,
+        _ => true,
+    }
 }
@@ -0,0 +1,122 @@
+use std::net::Shutdown;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use mioco::tcp::TcpStream;
+use mioco::sync::mpsc::{channel, Receiver, Sender};
+use mioco::timer::Timer;
+use mioco;
+
+use relay::Activity;
+use utils::Result;
+
+#[derive(Fail, Debug)]
+#[fail(display = "{} timed out", _0)]
+pub struct TimeoutError(pub &'static str);
+
+/// Shuts down a stream if not disarmed in time, which interrupts blocking IO on it
+pub struct Watchdog {
+    disarm: Sender<()>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn arm(stream: &TcpStream, timeout_ms: u64) -> Result<Watchdog> {
+        let stream = stream.try_clone()?;
+        let (tx, rx) = channel::<()>();
+        let fired = Arc::new(AtomicBool::new(false));
+        let fired_clone = fired.clone();
+        mioco::spawn(move || {
+            let mut timer = Timer::new();
+            timer.set_timeout(timeout_ms as i64);
+            select!(
+                r:rx => {},
+                r:timer => {
+                    fired_clone.store(true, Ordering::SeqCst);
+                    stream.shutdown(Shutdown::Both).is_ok();
+                },
+            );
+        });
+        Ok(Watchdog { disarm: tx, fired: fired })
+    }
+
+    /// Returns whether the watchdog has fired
+    pub fn disarm(self) -> bool {
+        self.disarm.send(()).is_ok();
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// Runs blocking IO on `stream` in `f`, failing with `TimeoutError` if it takes longer than `timeout_ms`.
+/// 0 disables the timeout.
+pub fn with_timeout<T, F: FnOnce(&mut TcpStream) -> Result<T>>(stream: &mut TcpStream, timeout_ms: u64, what: &'static str, f: F) -> Result<T> {
+    if timeout_ms == 0 {
+        return f(stream);
+    }
+    let watchdog = Watchdog::arm(stream, timeout_ms)?;
+    let ret = f(stream);
+    if watchdog.disarm() {
+        return Err(TimeoutError(what))?;
+    }
+    ret
+}
+
+pub fn is_timeout(e: &::failure::Error) -> bool {
+    e.downcast_ref::<TimeoutError>().is_some()
+}
+
+/// Limits of a relay in milliseconds, 0 disables the limit
+#[derive(Debug, Clone, Default)]
+pub struct RelayTimeouts {
+    /// No data in either direction
+    pub idle: u64,
+    /// One direction finished while the other is still open
+    pub half_closed: u64,
+    pub lifetime: u64,
+}
+
+impl RelayTimeouts {
+    pub fn is_enabled(&self) -> bool {
+        self.idle > 0 || self.half_closed > 0 || self.lifetime > 0
+    }
+}
+
+/// Waits until both directions of a relay report on `done`, shutting down `streams` if a limit in `timeouts` is hit first
+pub fn supervise_relay(streams: &[&TcpStream], activity: &Activity, timeouts: &RelayTimeouts, done: Receiver<()>) -> Result<()> {
+    let mut finished = 0;
+    let mut half_closed_at = 0;
+    while finished < 2 {
+        let now = activity.elapsed_ms();
+        let deadlines = [
+            (timeouts.idle, activity.last_ms(), "Idle connection"),
+            (if finished == 1 { timeouts.half_closed } else { 0 }, half_closed_at, "Half-closed connection"),
+            (timeouts.lifetime, 0, "Connection lifetime"),
+        ];
+        let mut wait = None;
+        for &(timeout, since, what) in deadlines.iter().filter(|x| x.0 > 0) {
+            let deadline = since + timeout;
+            if deadline <= now {
+                for stream in streams {
+                    stream.shutdown(Shutdown::Both).is_ok();
+                }
+                return Err(TimeoutError(what))?;
+            }
+            wait = Some(wait.unwrap_or(deadline - now).min(deadline - now));
+        }
+        match wait {
+            Some(ms) => {
+                let mut timer = Timer::new();
+                timer.set_timeout(ms as i64);
+                select!(
+                    r:done => {},
+                    r:timer => {},
+                );
+            },
+            None => select!(r:done => {}),
+        };
+        while done.try_recv().is_ok() {
+            finished += 1;
+            half_closed_at = activity.elapsed_ms();
+        }
+    }
+    Ok(())
+}
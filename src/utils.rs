@@ -3,13 +3,24 @@ use std::mem::size_of_val;
 use std::io::Error as IoError;
 use std::os::unix::io::RawFd;
 use failure::Error;
-use libc::{c_int, c_void, socklen_t, setsockopt};
+use libc::{c_int, c_void, socklen_t, setsockopt, linger, EMFILE, ENFILE, SOL_SOCKET, SO_LINGER};
 
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub const IP_TRANSPARENT: c_int = 19;
 
+/// Milliseconds to wait before accepting again when out of file descriptors
+pub const ACCEPT_BACKOFF_MS: u64 = 100;
+
+/// Whether the process or the system ran out of file descriptors
+pub fn is_fd_exhausted(e: &IoError) -> bool {
+    match e.raw_os_error() {
+        Some(EMFILE) | Some(ENFILE) => true,
+        _ => false,
+    }
+}
+
 pub fn setsockopt_bool(fd: RawFd, level: c_int, name: c_int, val: bool) -> Result<()> {
     let flag: c_int = if val { 1 } else { 0 };
     match unsafe {
@@ -19,3 +30,14 @@ pub fn setsockopt_bool(fd: RawFd, level: c_int, name: c_int, val: bool) -> Resul
         _ => Err(IoError::last_os_error().into()),
     }
 }
+
+/// Makes closing the socket send RST instead of FIN
+pub fn set_linger_zero(fd: RawFd) -> Result<()> {
+    let val = linger { l_onoff: 1, l_linger: 0 };
+    match unsafe {
+        setsockopt(fd as c_int, SOL_SOCKET, SO_LINGER, &val as *const _ as *const c_void, size_of_val(&val) as socklen_t)
+    } {
+        0 => Ok(()),
+        _ => Err(IoError::last_os_error().into()),
+    }
+}